@@ -0,0 +1,63 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, SamplingMode};
+use ipnet::IpNet;
+
+use wgdhc::common::allocator::AddressIndex;
+
+// пул /16, в котором заняты все адреса, кроме последних FREE
+const FREE: usize = 16;
+
+fn pool() -> IpNet {
+    "10.11.0.0/16".parse().unwrap()
+}
+
+fn used() -> Vec<IpAddr> {
+    let hosts: Vec<_> = pool().hosts().collect();
+    hosts[..hosts.len() - FREE].to_vec()
+}
+
+// прежний поиск: перебор хостов пула с проверкой по списку занятых адресов
+fn linear_find(pool: &IpNet, used: &[IpAddr]) -> Option<IpAddr> {
+    pool.hosts().find(|addr| !used.contains(addr))
+}
+
+fn allocation(c: &mut Criterion) {
+    let pool = pool();
+    let used = used();
+    let mut index = AddressIndex::new(pool);
+    used.iter().for_each(|addr| index.take(*addr));
+
+    let mut group = c.benchmark_group("nearly full /16");
+    // прежний поиск занимает секунды на итерацию, поэтому итераций в каждом замере поровну
+    group
+        .sampling_mode(SamplingMode::Flat)
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(30));
+    group.bench_function("linear scan", |b| {
+        b.iter(|| linear_find(black_box(&pool), black_box(&used)))
+    });
+    group.bench_function("index", |b| {
+        b.iter(|| {
+            let addr = index.free_in(&pool, 0).unwrap();
+            index.take(addr);
+            index.release(black_box(addr));
+        })
+    });
+    group.bench_function("index build", |b| {
+        b.iter_batched(
+            || used.clone(),
+            |used| {
+                let mut index = AddressIndex::new(pool);
+                used.iter().for_each(|addr| index.take(*addr));
+                index
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, allocation);
+criterion_main!(benches);
@@ -0,0 +1,184 @@
+use std::io::Write as _;
+
+use clap::{Args, Subcommand};
+
+use crate::client::{self, CredentialInterceptor, TlsArguments};
+use crate::common::proto::{
+    admin_service_client::AdminServiceClient, peer_event::Event, GetPeerRequest,
+    GetPoolUsageRequest, ListPeersRequest, MovePeerRequest, Peer, RevokePeerRequest,
+    SetDrainModeRequest, WatchPeersRequest,
+};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AdminCommand {
+    #[command(name = "ls", about = "lists peers")]
+    Ls {
+        #[clap(long, help = "only peers of this account")]
+        account: Option<String>,
+    },
+    #[command(name = "get", about = "shows peer by its public key")]
+    Get { public_key: String },
+    #[command(
+        name = "revoke",
+        alias = "rm",
+        about = "removes peer by its public key"
+    )]
+    Revoke { public_key: String },
+    #[command(
+        name = "move",
+        about = "moves peer to another account, with account_pools into its subnet"
+    )]
+    Move { public_key: String, account: String },
+    #[command(name = "drain", about = "stops or resumes accepting new peers")]
+    Drain { mode: Switch },
+    #[command(name = "pool", about = "shows address pool usage")]
+    Pool,
+    #[command(
+        name = "watch",
+        about = "prints peers and then their changes as they happen"
+    )]
+    Watch {
+        #[clap(long, help = "only peers of this account")]
+        account: Option<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct Arguments {
+    #[clap(
+        long,
+        help = "wg dhc server endpoint, including http or https protocole and port"
+    )]
+    pub host: String,
+    #[clap(long, help = "name of the admin credential from the server config")]
+    pub user: String,
+    #[clap(
+        long,
+        env = "WGDHC_ADMIN_TOKEN",
+        hide_env_values = true,
+        help = "token of the admin credential"
+    )]
+    pub token: String,
+    #[command(flatten)]
+    pub tls: TlsArguments,
+    #[command(subcommand)]
+    pub command: AdminCommand,
+}
+
+// каждая строка события начинается с его типа, снимок выводится строками `peer`
+fn format_event(event: &Event) -> String {
+    match event {
+        Event::Snapshot(snapshot) => snapshot
+            .peers
+            .iter()
+            .map(|peer| format!("peer\t{}", format_peer(peer)))
+            .collect(),
+        Event::Added(peer) => format!("added\t{}", format_peer(peer)),
+        Event::Removed(peer) => format!("removed\t{}", format_peer(peer)),
+        Event::Renewed(peer) => format!("renewed\t{}", format_peer(peer)),
+        Event::KeyRotated(rotated) => format!(
+            "key_rotated\t{}\t{}",
+            rotated.old_public_key,
+            rotated.peer.as_ref().map(format_peer).unwrap_or_default()
+        ),
+    }
+}
+
+fn format_peer(peer: &Peer) -> String {
+    let state = if peer.offered { "offered" } else { "bound" };
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+        peer.account,
+        peer.addresses.join(","),
+        peer.public_key,
+        state,
+        peer.expires_at,
+        peer.delegated_prefixes.join(","),
+        peer.advertised_routes.join(",")
+    )
+}
+
+pub async fn execute(args: &Arguments) -> Result<String, Box<dyn std::error::Error>> {
+    let channel = client::channel(&args.host, &args.tls).await?;
+    let interceptor = CredentialInterceptor::new(&args.user, Some(&args.token))?;
+    let mut admin = AdminServiceClient::with_interceptor(channel, interceptor);
+
+    // вывод разделен табуляцией, чтобы его было удобно разбирать скриптами
+    let output = match &args.command {
+        AdminCommand::Ls { account } => {
+            let request = ListPeersRequest {
+                account: account.clone().unwrap_or_default(),
+            };
+            let response = admin.list_peers(request).await?.into_inner();
+            response.peers.iter().map(format_peer).collect()
+        }
+        AdminCommand::Get { public_key } => {
+            let request = GetPeerRequest {
+                public_key: public_key.clone(),
+            };
+            let response = admin.get_peer(request).await?.into_inner();
+            response.peer.as_ref().map(format_peer).unwrap_or_default()
+        }
+        AdminCommand::Revoke { public_key } => {
+            let request = RevokePeerRequest {
+                public_key: public_key.clone(),
+            };
+            let response = admin.revoke_peer(request).await?.into_inner();
+            response.peer.as_ref().map(format_peer).unwrap_or_default()
+        }
+        AdminCommand::Move {
+            public_key,
+            account,
+        } => {
+            let request = MovePeerRequest {
+                public_key: public_key.clone(),
+                account: account.clone(),
+            };
+            let response = admin.move_peer(request).await?.into_inner();
+            response.peer.as_ref().map(format_peer).unwrap_or_default()
+        }
+        AdminCommand::Drain { mode } => {
+            let request = SetDrainModeRequest {
+                enabled: matches!(mode, Switch::On),
+            };
+            let response = admin.set_drain_mode(request).await?.into_inner();
+            format!("draining\t{}\n", response.enabled)
+        }
+        AdminCommand::Pool => {
+            let usage = admin
+                .get_pool_usage(GetPoolUsageRequest {})
+                .await?
+                .into_inner();
+            let mut output = String::from("network\ttotal\tused\tfree\n");
+            for pool in usage.pools {
+                output.push_str(&format!(
+                    "{}\t{}\t{}\t{}\n",
+                    pool.network, pool.total, pool.used, pool.free
+                ));
+            }
+            output.push_str(&format!("draining\t{}\n", usage.draining));
+            output
+        }
+        AdminCommand::Watch { account } => {
+            let request = WatchPeersRequest {
+                account: account.clone().unwrap_or_default(),
+            };
+            let mut stream = admin.watch_peers(request).await?.into_inner();
+            // события печатаются по мере поступления, а не после завершения потока
+            while let Some(event) = stream.message().await? {
+                if let Some(event) = &event.event {
+                    print!("{}", format_event(event));
+                    std::io::stdout().flush()?;
+                }
+            }
+            String::new()
+        }
+    };
+    Ok(output)
+}
@@ -0,0 +1,241 @@
+pub use crate::common::proto::{
+    admin_service_server::{AdminService, AdminServiceServer},
+    peer_event::Event,
+    GetPeerRequest, GetPeerResponse, GetPoolUsageRequest, GetPoolUsageResponse, KeyRotated,
+    ListPeersRequest, ListPeersResponse, MovePeerRequest, MovePeerResponse, Peer, PeerEvent,
+    PeerSnapshot, RevokePeerRequest, RevokePeerResponse, SetDrainModeRequest, SetDrainModeResponse,
+    WatchPeersRequest,
+};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::pin::Pin;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt as _,
+};
+use tonic::Response;
+
+use crate::auth;
+use crate::common::{
+    config::{Role, CONFIG},
+    events::{self, Peers},
+    storage::{self, PeerInfo},
+    wg::{FromBase64, IntoBase64, PublicKey},
+    wgcli,
+};
+use crate::service;
+
+pub struct AdminServiceImpl {}
+
+fn to_proto(account: &str, public_key: &PublicKey, info: &PeerInfo) -> Peer {
+    Peer {
+        account: account.to_string(),
+        public_key: public_key.into_base_64(),
+        addresses: info.addresses.iter().map(IpAddr::to_string).collect(),
+        expires_at: info.expires_at.unwrap_or(0),
+        offered: info.offered,
+        delegated_prefixes: info.delegated.iter().map(IpNet::to_string).collect(),
+        advertised_routes: info.routes.iter().map(IpNet::to_string).collect(),
+    }
+}
+
+// пустой account означает все пиры
+fn list(peers: &Peers, account: &str) -> Vec<Peer> {
+    let mut peers: Vec<_> = peers
+        .iter()
+        .filter(|(name, _)| account.is_empty() || *name == account)
+        .flat_map(|(account, peers)| {
+            peers
+                .iter()
+                .map(|(public_key, info)| to_proto(account, public_key, info))
+        })
+        .collect();
+    peers.sort_by(|a, b| (&a.account, &a.addresses).cmp(&(&b.account, &b.addresses)));
+    peers
+}
+
+fn event_to_proto(event: &events::PeerEvent, filter: &str) -> Option<PeerEvent> {
+    let (account, event) = match event {
+        events::PeerEvent::Added {
+            account,
+            public_key,
+            info,
+        } => (account, Event::Added(to_proto(account, public_key, info))),
+        events::PeerEvent::Removed {
+            account,
+            public_key,
+            info,
+        } => (account, Event::Removed(to_proto(account, public_key, info))),
+        events::PeerEvent::Renewed {
+            account,
+            public_key,
+            info,
+        } => (account, Event::Renewed(to_proto(account, public_key, info))),
+        events::PeerEvent::KeyRotated {
+            account,
+            old_public_key,
+            public_key,
+            info,
+        } => (
+            account,
+            Event::KeyRotated(KeyRotated {
+                old_public_key: old_public_key.into_base_64(),
+                peer: Some(to_proto(account, public_key, info)),
+            }),
+        ),
+    };
+    (filter.is_empty() || account == filter).then_some(PeerEvent { event: Some(event) })
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_key(public_key: &str) -> tonic::Result<PublicKey> {
+    FromBase64::from_base_64(public_key)
+        .map_err(|e| tonic::Status::invalid_argument(format!("incorrect public key: {e}")))
+}
+
+#[tonic::async_trait]
+impl AdminService for AdminServiceImpl {
+    type WatchPeersStream = Pin<Box<dyn Stream<Item = tonic::Result<PeerEvent>> + Send>>;
+
+    async fn list_peers(
+        &self,
+        request: tonic::Request<ListPeersRequest>,
+    ) -> tonic::Result<tonic::Response<ListPeersResponse>> {
+        auth::require_role(&request, Role::ReadOnly)?;
+        let req = request.get_ref();
+        let storage = storage::read_storage().await;
+        Ok(Response::new(ListPeersResponse {
+            peers: list(&storage.peers, &req.account),
+        }))
+    }
+
+    async fn get_peer(
+        &self,
+        request: tonic::Request<GetPeerRequest>,
+    ) -> tonic::Result<tonic::Response<GetPeerResponse>> {
+        auth::require_role(&request, Role::ReadOnly)?;
+        let public_key = parse_key(&request.get_ref().public_key)?;
+        let storage = storage::read_storage().await;
+        let account = storage
+            .account_of(&public_key)
+            .ok_or(tonic::Status::not_found("no such peer"))?;
+        let info = &storage.peers[account][&public_key];
+        Ok(Response::new(GetPeerResponse {
+            peer: Some(to_proto(account, &public_key, info)),
+        }))
+    }
+
+    async fn revoke_peer(
+        &self,
+        request: tonic::Request<RevokePeerRequest>,
+    ) -> tonic::Result<tonic::Response<RevokePeerResponse>> {
+        auth::require_role(&request, Role::ReadWrite)?;
+        let public_key = parse_key(&request.get_ref().public_key)?;
+        let mut storage = storage::get_storage().await;
+        let account = storage
+            .account_of(&public_key)
+            .ok_or(tonic::Status::not_found("no such peer"))?
+            .to_string();
+        wgcli::remove_peer(&CONFIG.interface, &public_key).await?;
+        let info = storage.remove(&account, &public_key).unwrap();
+        events::publish(events::PeerEvent::Removed {
+            account: account.clone(),
+            public_key,
+            info: info.clone(),
+        });
+        Ok(Response::new(RevokePeerResponse {
+            peer: Some(to_proto(&account, &public_key, &info)),
+        }))
+    }
+
+    async fn move_peer(
+        &self,
+        request: tonic::Request<MovePeerRequest>,
+    ) -> tonic::Result<tonic::Response<MovePeerResponse>> {
+        auth::require_role(&request, Role::ReadWrite)?;
+        let req = request.get_ref();
+        if req.account.is_empty() {
+            return Err(tonic::Status::invalid_argument("account is required"));
+        }
+        let public_key = parse_key(&req.public_key)?;
+        let mut storage = storage::get_storage().await;
+        let from = storage
+            .account_of(&public_key)
+            .ok_or(tonic::Status::not_found("no such peer"))?
+            .to_string();
+        let old = storage.peers[&from][&public_key].clone();
+        let info = storage.move_peer(&public_key, &req.account).unwrap()?;
+        if info.addresses != old.addresses {
+            if let Err(error) = service::wireguard_add_peer(&public_key, &info).await {
+                eprintln!(
+                    "peer {} is moved, but interface is not updated ({}), reconciler will retry",
+                    public_key.into_base_64(),
+                    error.message()
+                );
+            }
+        }
+        // перенос в другой account виден как удаление и добавление
+        events::publish(events::PeerEvent::Removed {
+            account: from,
+            public_key,
+            info: old,
+        });
+        events::publish(events::PeerEvent::Added {
+            account: req.account.clone(),
+            public_key,
+            info: info.clone(),
+        });
+        Ok(Response::new(MovePeerResponse {
+            peer: Some(to_proto(&req.account, &public_key, &info)),
+        }))
+    }
+
+    async fn set_drain_mode(
+        &self,
+        request: tonic::Request<SetDrainModeRequest>,
+    ) -> tonic::Result<tonic::Response<SetDrainModeResponse>> {
+        auth::require_role(&request, Role::ReadWrite)?;
+        let mut storage = storage::get_storage().await;
+        storage.draining = request.get_ref().enabled;
+        Ok(Response::new(SetDrainModeResponse {
+            enabled: storage.draining,
+        }))
+    }
+
+    async fn get_pool_usage(
+        &self,
+        request: tonic::Request<GetPoolUsageRequest>,
+    ) -> tonic::Result<tonic::Response<GetPoolUsageResponse>> {
+        auth::require_role(&request, Role::ReadOnly)?;
+        let storage = storage::read_storage().await;
+        Ok(Response::new(GetPoolUsageResponse {
+            pools: storage.pool_usage(),
+            draining: storage.draining,
+        }))
+    }
+
+    async fn watch_peers(
+        &self,
+        request: tonic::Request<WatchPeersRequest>,
+    ) -> tonic::Result<tonic::Response<Self::WatchPeersStream>> {
+        auth::require_role(&request, Role::ReadOnly)?;
+        let account = request.into_inner().account;
+        let (peers, receiver) =
+            storage::read_locked(|storage| (storage.peers.clone(), events::subscribe())).await;
+        let snapshot = PeerEvent {
+            event: Some(Event::Snapshot(PeerSnapshot {
+                peers: list(&peers, &account),
+            })),
+        };
+        let updates = BroadcastStream::new(receiver).filter_map(move |event| match event {
+            Ok(event) => event_to_proto(&event, &account).map(Ok),
+            // подписчик не успел за событиями, после переподключения он получит новый снимок
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => Some(Err(tonic::Status::data_loss(
+                format!("{skipped} events were skipped, watch again"),
+            ))),
+        });
+        Ok(Response::new(Box::pin(
+            tokio_stream::once(Ok(snapshot)).chain(updates),
+        )))
+    }
+}
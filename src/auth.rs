@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use sha2::{Digest, Sha256};
+use tonic::{service::Interceptor, Request, Status};
+
+use crate::common::config::{Auth, Role, CONFIG};
+
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, account: &str, secret: &str) -> bool;
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub struct StaticTokens {
+    tokens: HashMap<String, String>,
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(&self, account: &str, secret: &str) -> bool {
+        self.tokens
+            .get(account)
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), secret.as_bytes()))
+    }
+}
+
+pub struct Htpasswd {
+    hashes: HashMap<String, String>,
+    // sha256 последнего подошедшего секрета account, чтобы bcrypt не считался на каждый запрос
+    verified: Mutex<HashMap<String, [u8; 32]>>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let hashes = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(account, hash)| (account.to_string(), hash.to_string()))
+            .collect();
+        Ok(Htpasswd {
+            hashes,
+            verified: Mutex::default(),
+        })
+    }
+}
+
+impl Authenticator for Htpasswd {
+    fn authenticate(&self, account: &str, secret: &str) -> bool {
+        let Some(hash) = self.hashes.get(account) else {
+            return false;
+        };
+        let digest: [u8; 32] = Sha256::digest(secret.as_bytes()).into();
+        let cached = self.verified.lock().unwrap().get(account).copied();
+        if cached.is_some_and(|cached| constant_time_eq(&cached, &digest)) {
+            return true;
+        }
+        // bcrypt занимает поток на ~100 мс, перехватчик синхронный, поэтому
+        // tokio переносит остальные задачи этого потока на другие
+        let valid = tokio::task::block_in_place(|| bcrypt::verify(secret, hash).unwrap_or(false));
+        if valid {
+            self.verified
+                .lock()
+                .unwrap()
+                .insert(account.to_string(), digest);
+        }
+        valid
+    }
+}
+
+pub fn from_config(auth: &Auth) -> std::io::Result<Arc<dyn Authenticator>> {
+    Ok(match auth {
+        Auth::Tokens { tokens } => Arc::new(StaticTokens {
+            tokens: tokens.clone(),
+        }),
+        Auth::Htpasswd { file } => Arc::new(Htpasswd::load(file)?),
+    })
+}
+
+// account, подтвержденный перехватчиком, кладется в extensions запроса
+#[derive(Clone)]
+pub struct AuthenticatedAccount(pub String);
+
+// ожидает заголовок `authorization: Basic base64(account:secret)`
+#[derive(Clone)]
+pub struct AuthInterceptor {
+    authenticator: Option<Arc<dyn Authenticator>>,
+}
+
+impl AuthInterceptor {
+    pub fn from_config() -> std::io::Result<Self> {
+        Ok(AuthInterceptor {
+            authenticator: CONFIG.auth.as_ref().map(from_config).transpose()?,
+        })
+    }
+}
+
+fn parse_basic(request: &Request<()>) -> Option<(String, String)> {
+    let value = request.metadata().get("authorization")?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (account, secret) = decoded.split_once(':')?;
+    Some((account.to_string(), secret.to_string()))
+}
+
+// при mTLS account - это CN из subject сертификата клиента
+fn certificate_account(request: &Request<()>) -> Option<String> {
+    let certs = request.peer_certs()?;
+    let (_, certificate) = x509_parser::parse_x509_certificate(certs.first()?.get_ref()).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?;
+    common_name.as_str().ok().map(str::to_string)
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(account) = certificate_account(&request) {
+            request
+                .extensions_mut()
+                .insert(AuthenticatedAccount(account));
+            return Ok(request);
+        }
+        let Some(authenticator) = &self.authenticator else {
+            return Ok(request);
+        };
+        let (account, secret) = parse_basic(&request)
+            .ok_or_else(|| Status::unauthenticated("missing or malformed credentials"))?;
+        if !authenticator.authenticate(&account, &secret) {
+            return Err(Status::unauthenticated("invalid credentials"));
+        }
+        request
+            .extensions_mut()
+            .insert(AuthenticatedAccount(account));
+        Ok(request)
+    }
+}
+
+// проверяет, что запрос относится к тому account, под которым клиент вошел
+#[allow(clippy::result_large_err)]
+pub fn check_account<T>(request: &Request<T>, account: &str) -> tonic::Result<()> {
+    match request.extensions().get::<AuthenticatedAccount>() {
+        Some(AuthenticatedAccount(authenticated)) if authenticated != account => Err(
+            Status::permission_denied(format!("credentials are not valid for '{}'", account)),
+        ),
+        _ => Ok(()),
+    }
+}
+
+// роль администратора, подтвержденная AdminInterceptor
+#[derive(Clone, Copy)]
+pub struct AdminRole(pub Role);
+
+// те же `Basic base64(name:token)`, но по учетным записям из секции admin
+#[derive(Clone)]
+pub struct AdminInterceptor;
+
+impl Interceptor for AdminInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let (name, token) = parse_basic(&request)
+            .ok_or_else(|| Status::unauthenticated("missing or malformed credentials"))?;
+        let credential = CONFIG
+            .admin
+            .get(&name)
+            .filter(|credential| constant_time_eq(credential.token.as_bytes(), token.as_bytes()))
+            .ok_or_else(|| Status::unauthenticated("invalid credentials"))?;
+        request.extensions_mut().insert(AdminRole(credential.role));
+        Ok(request)
+    }
+}
+
+#[allow(clippy::result_large_err)]
+pub fn require_role<T>(request: &Request<T>, role: Role) -> tonic::Result<()> {
+    match request.extensions().get::<AdminRole>() {
+        Some(AdminRole(granted)) if *granted >= role => Ok(()),
+        _ => Err(Status::permission_denied(format!(
+            "{:?} role is required",
+            role
+        ))),
+    }
+}
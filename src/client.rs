@@ -1,25 +1,144 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::process::{ExitStatus, Stdio};
 
-use clap::Args;
+use clap::{ArgMatches, Args, FromArgMatches, Subcommand};
 use ipnet::IpNet;
 use tokio::{io::AsyncWriteExt, process::Command};
 
 use crate::common::wg::FromBase64;
-use crate::common::wg::{self, IntoBase64, KeyPair, PrivateKey};
+use crate::common::wg::{self, IntoBase64, KeyPair, PresharedKey, PrivateKey};
+use crate::common::{proof, storage, wgcli};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use tonic::codegen::InterceptedService;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::Interceptor;
 use tonic::transport::channel::Endpoint as TEndpoint;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
 
-use crate::common::proto::{dhc_service_client::DhcServiceClient, ReserveIpRequest};
+use crate::common::proto::{
+    dhc_service_client::DhcServiceClient, ConfirmIpRequest, GetChallengeRequest,
+    GetServerInfoRequest, ReleaseIpRequest, RenewLeaseRequest, ReserveIpRequest, ReserveIpResponse,
+};
 
 #[derive(Debug, Args)]
-pub struct Arguments {
+pub struct ServerArguments {
     #[clap(help = "wg dhc server endpoint, including http or https protocole and port")]
     pub host: String,
     #[clap(help = "any string that identifies you for admin's conviniece")]
     pub account: String,
+    #[clap(
+        long,
+        env = "WGDHC_CREDENTIAL",
+        hide_env_values = true,
+        help = "token or password of the account, if the server requires one"
+    )]
+    pub credential: Option<String>,
+    #[command(flatten)]
+    pub tls: TlsArguments,
+}
+
+#[derive(Debug, Args)]
+pub struct TlsArguments {
+    #[clap(long, help = "CA certificate to verify the server with, PEM")]
+    pub ca: Option<PathBuf>,
+    #[clap(long, requires = "key", help = "client certificate for mTLS, PEM")]
+    pub cert: Option<PathBuf>,
+    #[clap(
+        long,
+        requires = "cert",
+        help = "private key of the client certificate, PEM"
+    )]
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct UpArguments {
+    #[command(flatten)]
+    pub server: ServerArguments,
     #[clap(default_value_t={"wg0".to_string()}, help="wg interface name to be created")]
     pub interface: String,
-    #[clap(default_value_t={5}, help="persistent_keepalive parameter for wireguard")]
-    pub persistent_keepalive: usize,
+    #[clap(help = "persistent_keepalive parameter for wireguard, overrides the one from server")]
+    pub persistent_keepalive: Option<u16>,
+    #[clap(long, help = "address to ask for instead of the first free one")]
+    pub address: Option<IpAddr>,
+    #[clap(
+        long,
+        help = "length of a prefix to be routed to this peer, e.g. 29 or 64"
+    )]
+    pub delegate: Option<u8>,
+    #[clap(
+        long = "route",
+        help = "subnet behind this peer to be routed to it by the others, can be repeated"
+    )]
+    pub routes: Vec<IpNet>,
+}
+
+#[derive(Debug, Args)]
+pub struct InterfaceArguments {
+    #[command(flatten)]
+    pub server: ServerArguments,
+    #[clap(default_value_t={"wg0".to_string()}, help="wg interface name created by up")]
+    pub interface: String,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ClientCommand {
+    #[command(name = "up", about = "reserves ip and inits wg interface, the default")]
+    Up(UpArguments),
+    #[command(name = "down", about = "releases ip and removes wg interface")]
+    Down(InterfaceArguments),
+    #[command(name = "renew", about = "renews the lease of ip of wg interface")]
+    Renew(InterfaceArguments),
+    #[command(
+        name = "rotate",
+        about = "replaces the key of wg interface keeping its ip"
+    )]
+    Rotate(InterfaceArguments),
+    #[command(
+        name = "info",
+        about = "shows server network and pool without reserving ip"
+    )]
+    Info(ServerArguments),
+}
+
+#[derive(Debug)]
+pub struct Arguments {
+    pub command: Option<ClientCommand>,
+    pub up: Option<UpArguments>,
+}
+
+// derive не заполняет группу аргументов для вложенных flatten, из-за чего
+// `Option<UpArguments>` всегда оставался None, поэтому разбор написан вручную
+impl FromArgMatches for Arguments {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let command = matches
+            .subcommand_name()
+            .map(|_| ClientCommand::from_arg_matches(matches))
+            .transpose()?;
+        let up = command
+            .is_none()
+            .then(|| UpArguments::from_arg_matches(matches))
+            .transpose()?;
+        Ok(Arguments { command, up })
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+impl Args for Arguments {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        ClientCommand::augment_subcommands(UpArguments::augment_args(cmd))
+            .args_conflicts_with_subcommands(true)
+            .subcommand_negates_reqs(true)
+    }
+
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        Self::augment_args(cmd)
+    }
 }
 
 fn check(status: ExitStatus, error: &str) -> Result<(), Box<dyn std::error::Error>> {
@@ -29,44 +148,86 @@ fn check(status: ExitStatus, error: &str) -> Result<(), Box<dyn std::error::Erro
     Ok(())
 }
 
-pub async fn setup_wireguard_interface(
+// Настройка приватного ключа через /dev/stdin
+async fn set_private_key(
     private_key: &PrivateKey,
-    internal_address: &str,
-    args: &Arguments,
+    interface: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Создание интерфейса wg0
+    let mut child = Command::new("wg")
+        .args(["set", interface, "private-key", "/dev/stdin"])
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    let stdin = child.stdin.as_mut().ok_or("Failed to open stdin")?;
+    stdin
+        .write_all(private_key.into_base_64().as_bytes())
+        .await?;
+    stdin.flush().await?;
+    drop(child.stdin.take());
+    check(child.wait().await?, "Failed to set private key")
+}
+
+// Создание интерфейса wg0
+async fn create_interface(interface: &str) -> Result<(), Box<dyn std::error::Error>> {
     check(
         Command::new("ip")
-            .args(["link", "add", &args.interface, "type", "wireguard"])
+            .args(["link", "add", interface, "type", "wireguard"])
             .status()
             .await?,
         "Failed to add interface",
-    )?;
+    )
+}
 
-    // Назначение IP адреса интерфейсу wg0
+async fn remove_interface(interface: &str) -> Result<(), Box<dyn std::error::Error>> {
     check(
         Command::new("ip")
-            .args(["address", "add", internal_address, "dev", &args.interface])
+            .args(["link", "del", "dev", interface])
             .status()
             .await?,
-        "Failed to set ip to interface",
-    )?;
+        "Failed to remove interface",
+    )
+}
 
-    // Настройка приватного ключа через /dev/stdin
-    let mut child = Command::new("wg")
-        .args(["set", &args.interface, "private-key", "/dev/stdin"])
-        .stdin(Stdio::piped())
-        .spawn()?;
+pub async fn setup_wireguard_interface(
+    private_key: &PrivateKey,
+    addresses: &[IpNet],
+    mtu: Option<u32>,
+    args: &UpArguments,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Назначение IP адресов интерфейсу wg0, по одному на каждый пул сервера
+    for address in addresses {
+        check(
+            Command::new("ip")
+                .args([
+                    "address",
+                    "add",
+                    &address.to_string(),
+                    "dev",
+                    &args.interface,
+                ])
+                .status()
+                .await?,
+            "Failed to set ip to interface",
+        )?;
+    }
 
-    let stdin = child.stdin.as_mut().ok_or("Failed to open stdin")?;
-    stdin
-        .write_all(private_key.into_base_64().as_bytes())
-        .await?;
-    stdin.flush().await?;
-    let status = child.wait().await?;
+    set_private_key(private_key, &args.interface).await?;
 
-    if !status.success() {
-        return Err("Failed to set private key".into());
+    if let Some(mtu) = mtu {
+        check(
+            Command::new("ip")
+                .args([
+                    "link",
+                    "set",
+                    "mtu",
+                    &mtu.to_string(),
+                    "dev",
+                    &args.interface,
+                ])
+                .status()
+                .await?,
+            "Failed to set mtu",
+        )?;
     }
     // Поднятие интерфейса wg0
     check(
@@ -81,57 +242,563 @@ pub async fn setup_wireguard_interface(
 }
 async fn wireguard_add_peer(
     public_key: &wg::PublicKey,
-    internal_address: IpNet,
+    preshared_key: Option<&PresharedKey>,
+    allowed_ips: &[IpNet],
     endpoint: &str,
-    args: &Arguments,
-) -> tonic::Result<()> {
+    persistent_keepalive: u16,
+    args: &UpArguments,
+) -> Result<(), Box<dyn std::error::Error>> {
     let pub_key: String = public_key.into_base_64();
-    let status = tokio::process::Command::new("wg")
-        .args([
-            "set",
-            &args.interface,
-            "peer",
-            &pub_key,
-            "endpoint",
-            endpoint,
-            "allowed-ips",
-            &internal_address.trunc().to_string(),
-            "persistent-keepalive",
-            &args.persistent_keepalive.to_string(),
-        ])
-        .status()
-        .await
-        .map_err(|err| tonic::Status::internal(format!("failed to run wg: {}", err)))?;
+    let allowed_ips = allowed_ips
+        .iter()
+        .map(IpNet::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let persistent_keepalive = persistent_keepalive.to_string();
+    let mut command = Command::new("wg");
+    command.args([
+        "set",
+        &args.interface,
+        "peer",
+        &pub_key,
+        "endpoint",
+        endpoint,
+        "allowed-ips",
+        &allowed_ips,
+        "persistent-keepalive",
+        &persistent_keepalive,
+    ]);
+    // preshared key, как и приватный, передается через /dev/stdin
+    if preshared_key.is_some() {
+        command.args(["preshared-key", "/dev/stdin"]);
+    }
+    let mut child = command.stdin(Stdio::piped()).spawn()?;
+    let mut stdin = child.stdin.take().ok_or("Failed to open stdin")?;
+    if let Some(preshared_key) = preshared_key {
+        stdin
+            .write_all(preshared_key.into_base_64().as_bytes())
+            .await?;
+    }
+    drop(stdin);
+    check(child.wait().await?, "Failed to add server peer")
+}
 
-    if !status.success() {
-        return Err(tonic::Status::internal(format!(
-            "wg finished with {status}"
-        )));
+// первая таблица маршрутизации для полного туннеля, как у wg-quick
+const FIRST_FULL_TUNNEL_TABLE: u32 = 51820;
+
+fn family(net: &IpNet) -> &'static str {
+    match net {
+        IpNet::V4(_) => "-4",
+        IpNet::V6(_) => "-6",
+    }
+}
+
+async fn ip(args: &[&str], error: &str) -> Result<(), Box<dyn std::error::Error>> {
+    check(Command::new("ip").args(args).status().await?, error)
+}
+
+// первая таблица без маршрутов, у каждого интерфейса полного туннеля она своя
+async fn free_table() -> Result<u32, Box<dyn std::error::Error>> {
+    let mut table = FIRST_FULL_TUNNEL_TABLE;
+    loop {
+        let table_string = table.to_string();
+        let mut used = false;
+        for family in ["-4", "-6"] {
+            let output = Command::new("ip")
+                .args([family, "route", "show", "table", &table_string])
+                .stderr(Stdio::null())
+                .output()
+                .await?;
+            used |= !output.stdout.is_empty();
+        }
+        if !used {
+            return Ok(table);
+        }
+        table = table.checked_add(1).ok_or("No free routing table")?;
+    }
+}
+
+// правила ссылаются на таблицу интерфейса, поэтому по ней удаляются
+// только правила этого интерфейса, а не чужие правила с fwmark
+fn full_tunnel_rules(table: &str) -> [Vec<&str>; 2] {
+    [
+        vec!["not", "fwmark", table, "table", table],
+        vec![
+            "not",
+            "fwmark",
+            table,
+            "table",
+            "main",
+            "suppress_prefixlength",
+            "0",
+        ],
+    ]
+}
+
+// маршрут по умолчанию через wg уводит в туннель и трафик до самого endpoint,
+// поэтому он кладется в отдельную таблицу, а пакеты wg помечаются fwmark,
+// равным номеру таблицы; table запоминает занятую таблицу для отката
+async fn add_default_route(
+    net: &IpNet,
+    interface: &str,
+    table: &mut Option<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let table = match *table {
+        Some(table) => table,
+        None => {
+            let free = free_table().await?;
+            check(
+                Command::new("wg")
+                    .args(["set", interface, "fwmark", &free.to_string()])
+                    .status()
+                    .await?,
+                "Failed to set fwmark",
+            )?;
+            *table = Some(free);
+            free
+        }
+    };
+    let table = table.to_string();
+    let family = family(net);
+    let net = net.to_string();
+    ip(
+        &[
+            family, "route", "add", &net, "dev", interface, "table", &table,
+        ],
+        "Failed to add default route",
+    )
+    .await?;
+    for rule in full_tunnel_rules(&table) {
+        let args = [&[family, "rule", "add"], rule.as_slice()].concat();
+        ip(&args, "Failed to add routing rule").await?;
     }
     Ok(())
 }
 
-pub async fn execute(args: &Arguments) -> Result<(), Box<dyn std::error::Error>> {
-    let endpoint: String = (&args.host).into();
-    let endpoint = TEndpoint::from_shared(endpoint)?;
+async fn add_routes(
+    allowed_ips: &[IpNet],
+    addresses: &[IpNet],
+    interface: &str,
+    table: &mut Option<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for net in allowed_ips {
+        // до внутренних сетей маршрут появляется вместе с адресами интерфейса
+        if addresses.iter().any(|address| *net == address.trunc()) {
+            continue;
+        }
+        if net.prefix_len() == 0 {
+            add_default_route(net, interface, table).await?;
+            continue;
+        }
+        ip(
+            &[
+                family(net),
+                "route",
+                "replace",
+                &net.to_string(),
+                "dev",
+                interface,
+            ],
+            "Failed to add route",
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+// правила полного туннеля не удаляются вместе с интерфейсом; правила
+// семейства без маршрута по умолчанию не ставились, их ошибки не важны
+async fn remove_default_route_rules(table: u32) {
+    let table = table.to_string();
+    for family in ["-4", "-6"] {
+        for rule in full_tunnel_rules(&table) {
+            let _ = Command::new("ip")
+                .args([family, "rule", "del"])
+                .args(rule)
+                .stderr(Stdio::null())
+                .status()
+                .await;
+        }
+    }
+}
+
+async fn set_dns(dns: &[String], interface: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut child = Command::new("resolvconf")
+        .args(["-a", interface, "-m", "0", "-x"])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let stdin = child.stdin.as_mut().ok_or("Failed to open stdin")?;
+    for server in dns {
+        stdin
+            .write_all(format!("nameserver {}\n", server).as_bytes())
+            .await?;
+    }
+    stdin.flush().await?;
+    drop(child.stdin.take());
+    check(child.wait().await?, "Failed to set dns")
+}
+
+#[derive(Clone)]
+pub struct CredentialInterceptor {
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl CredentialInterceptor {
+    pub fn new(
+        account: &str,
+        credential: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let authorization = credential
+            .map(|credential| {
+                let basic = STANDARD.encode(format!("{}:{}", account, credential));
+                format!("Basic {}", basic).parse()
+            })
+            .transpose()?;
+        Ok(CredentialInterceptor { authorization })
+    }
+}
+
+impl Interceptor for CredentialInterceptor {
+    fn call(&mut self, mut request: tonic::Request<()>) -> tonic::Result<tonic::Request<()>> {
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        Ok(request)
+    }
+}
+
+async fn tls_config(tls: &TlsArguments) -> Result<ClientTlsConfig, std::io::Error> {
+    let mut config = ClientTlsConfig::new();
+    if let Some(ca) = &tls.ca {
+        config = config.ca_certificate(Certificate::from_pem(tokio::fs::read(ca).await?));
+    }
+    if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+        let cert = tokio::fs::read(cert).await?;
+        let key = tokio::fs::read(key).await?;
+        config = config.identity(Identity::from_pem(cert, key));
+    }
+    Ok(config)
+}
+
+pub async fn channel(
+    host: &str,
+    tls: &TlsArguments,
+) -> Result<Channel, Box<dyn std::error::Error>> {
+    let mut endpoint = TEndpoint::from_shared(host.to_string())?;
+    if endpoint.uri().scheme_str() == Some("https") || tls.ca.is_some() || tls.cert.is_some() {
+        endpoint = endpoint.tls_config(tls_config(tls).await?)?;
+    }
+    Ok(endpoint.connect().await?)
+}
+
+type Client = DhcServiceClient<InterceptedService<Channel, CredentialInterceptor>>;
+
+async fn connect(server: &ServerArguments) -> Result<Client, Box<dyn std::error::Error>> {
+    let channel = channel(&server.host, &server.tls).await?;
+    let interceptor = CredentialInterceptor::new(&server.account, server.credential.as_deref())?;
+    Ok(DhcServiceClient::with_interceptor(channel, interceptor))
+}
+
+async fn configure(
+    private_key: &PrivateKey,
+    response: &ReserveIpResponse,
+    args: &UpArguments,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // старый сервер присылает только один адрес
+    let addresses = match response.addresses.is_empty() {
+        true => vec![response.address.parse()?],
+        false => response
+            .addresses
+            .iter()
+            .map(|address| address.parse())
+            .collect::<Result<Vec<IpNet>, _>>()?,
+    };
+    let allowed_ips = response
+        .allowed_ips
+        .iter()
+        .map(|net| net.parse())
+        .collect::<Result<Vec<IpNet>, _>>()?;
+    // старый сервер не присылает AllowedIPs, тогда это только внутренние сети
+    let allowed_ips = match allowed_ips.is_empty() {
+        true => addresses.iter().map(IpNet::trunc).collect(),
+        false => allowed_ips,
+    };
+    let persistent_keepalive = match args.persistent_keepalive {
+        Some(persistent_keepalive) => persistent_keepalive,
+        None => response.persistent_keepalive.try_into()?,
+    };
+    let mtu = Some(response.mtu).filter(|mtu| *mtu != 0);
+    let preshared_key = match response.preshared_key.as_str() {
+        "" => None,
+        key => Some(PresharedKey::from_base_64(key)?),
+    };
+
+    // при ошибке откатывается только созданное здесь: уже существующий
+    // интерфейс с тем же именем не удаляется
+    create_interface(&args.interface).await?;
+    let mut table = None;
+    let result = async {
+        setup_wireguard_interface(private_key, &addresses, mtu, args).await?;
+        wireguard_add_peer(
+            &FromBase64::from_base_64(&response.server_public_key)?,
+            preshared_key.as_ref(),
+            &allowed_ips,
+            &response.endpoint,
+            persistent_keepalive,
+            args,
+        )
+        .await?;
+        add_routes(&allowed_ips, &addresses, &args.interface, &mut table).await?;
+        if !response.dns.is_empty() {
+            set_dns(&response.dns, &args.interface).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        if let Some(table) = table {
+            remove_default_route_rules(table).await;
+        }
+        let _ = remove_interface(&args.interface).await;
+    }
+    result
+}
+
+// nonce и доказательство владения приватным ключом для ReserveIp
+async fn prove_possession(
+    client: &mut Client,
+    private_key: &PrivateKey,
+    account: &str,
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let challenge = client
+        .get_challenge(GetChallengeRequest {})
+        .await?
+        .into_inner();
+    let nonce = STANDARD.decode(&challenge.nonce)?;
+    let proof = proof::prove(
+        private_key,
+        &FromBase64::from_base_64(&challenge.server_key)?,
+        &nonce,
+        account,
+    );
+    Ok((challenge.nonce, STANDARD.encode(proof)))
+}
+
+async fn up(args: &UpArguments) -> Result<(), Box<dyn std::error::Error>> {
     let keypair = KeyPair::gen();
 
-    let mut client = DhcServiceClient::connect(endpoint).await?;
+    let mut client = connect(&args.server).await?;
+    let (nonce, proof) =
+        prove_possession(&mut client, &keypair.private, &args.server.account).await?;
     let request = ReserveIpRequest {
-        account: args.account.clone(),
+        account: args.server.account.clone(),
         public_key: keypair.public.into_base_64(),
+        nonce,
+        proof,
+        requested_address: args.address.map(|x| x.to_string()).unwrap_or_default(),
+        delegated_prefix_len: args.delegate.map_or(0, u32::from),
+        advertised_routes: args.routes.iter().map(IpNet::to_string).collect(),
+        ..Default::default()
     };
     let response = client.reserve_ip(request).await?;
     let response = response.into_inner();
 
-    setup_wireguard_interface(&keypair.private, &response.address, args).await?;
-    wireguard_add_peer(
-        &FromBase64::from_base_64(&response.server_public_key)?,
-        response.address.parse()?,
-        &response.endpoint,
-        args,
-    )
-    .await?;
+    if let Err(error) = configure(&keypair.private, &response, args).await {
+        // адрес пока только предложен, возвращаем его сразу, не дожидаясь таймаута
+        let request = ReleaseIpRequest {
+            account: args.server.account.clone(),
+            public_key: keypair.public.into_base_64(),
+        };
+        let _ = client.release_ip(request).await;
+        return Err(error);
+    }
+
+    let request = ConfirmIpRequest {
+        account: args.server.account.clone(),
+        public_key: keypair.public.into_base_64(),
+    };
+    let confirmed = client.confirm_ip(request).await?.into_inner();
+    // маршрутизацию делегированных префиксов за интерфейсом настраивает сам пользователь
+    for prefix in &response.delegated_prefixes {
+        println!("delegated prefix {}", prefix);
+    }
+    print_lease(confirmed.expires_at);
+
+    Ok(())
+}
+
+fn print_lease(expires_at: u64) {
+    if expires_at == 0 {
+        println!("lease never expires");
+    } else {
+        println!(
+            "lease expires in {} seconds, use `client renew` to extend it",
+            expires_at.saturating_sub(storage::now())
+        );
+    }
+}
+
+async fn down(args: &InterfaceArguments) -> Result<(), Box<dyn std::error::Error>> {
+    let public_key = wgcli::public_key(&args.interface).await?;
+
+    // сначала освобождаем адрес: если сервер недоступен, интерфейс остается
+    // и down можно повторить
+    let mut client = connect(&args.server).await?;
+    let request = ReleaseIpRequest {
+        account: args.server.account.clone(),
+        public_key: public_key.into_base_64(),
+    };
+    client.release_ip(request).await?;
+
+    // fwmark задается только вместе с правилами полного туннеля и
+    // пропадает вместе с интерфейсом, поэтому читается до его удаления
+    let table = wgcli::fwmark(&args.interface).await?;
+    remove_interface(&args.interface).await?;
+    if let Some(table) = table {
+        remove_default_route_rules(table).await;
+    }
+    // dns мог быть и не задан, поэтому ошибки не важны
+    let _ = Command::new("resolvconf")
+        .args(["-d", &args.interface, "-f"])
+        .status()
+        .await;
 
     Ok(())
 }
+
+async fn renew(args: &InterfaceArguments) -> Result<(), Box<dyn std::error::Error>> {
+    let public_key = wgcli::public_key(&args.interface).await?;
+
+    let mut client = connect(&args.server).await?;
+    let request = RenewLeaseRequest {
+        account: args.server.account.clone(),
+        public_key: public_key.into_base_64(),
+    };
+    let response = client.renew_lease(request).await?.into_inner();
+    // старый сервер не присылает AllowedIPs
+    if !response.allowed_ips.is_empty() {
+        let allowed_ips = response
+            .allowed_ips
+            .iter()
+            .map(|net| net.parse())
+            .collect::<Result<Vec<IpNet>, _>>()?;
+        update_allowed_ips(&allowed_ips, &args.interface).await?;
+    }
+    print_lease(response.expires_at);
+
+    Ok(())
+}
+
+// маршруты к подсетям, которые объявили или убрали другие пиры, меняются
+// вместе с AllowedIPs; внутренние сети и полный туннель настроены в up
+async fn update_allowed_ips(
+    allowed_ips: &[IpNet],
+    interface: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let [server] = wgcli::dump(interface)
+        .await?
+        .try_into()
+        .map_err(|_| format!("Interface {} must have exactly one peer", interface))?;
+    if server.allowed_ips == allowed_ips {
+        return Ok(());
+    }
+    let list = allowed_ips
+        .iter()
+        .map(IpNet::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    check(
+        Command::new("wg")
+            .args(["set", interface, "peer", &server.public_key.into_base_64()])
+            .args(["allowed-ips", &list])
+            .status()
+            .await?,
+        "Failed to set allowed ips",
+    )?;
+    let mut table = wgcli::fwmark(interface).await?;
+    let added: Vec<_> = allowed_ips
+        .iter()
+        .filter(|net| !server.allowed_ips.contains(net))
+        .filter(|net| table.is_none() || net.prefix_len() != 0)
+        .copied()
+        .collect();
+    add_routes(&added, &[], interface, &mut table).await?;
+    for net in &server.allowed_ips {
+        if !allowed_ips.contains(net) && net.prefix_len() != 0 {
+            // маршрута могло и не быть, если сеть внутренняя
+            let _ = Command::new("ip")
+                .args([
+                    family(net),
+                    "route",
+                    "del",
+                    &net.to_string(),
+                    "dev",
+                    interface,
+                ])
+                .stderr(Stdio::null())
+                .status()
+                .await;
+        }
+    }
+    Ok(())
+}
+
+// адреса, префиксы и аренда переходят к новому ключу, сам интерфейс не пересоздается
+async fn rotate(args: &InterfaceArguments) -> Result<(), Box<dyn std::error::Error>> {
+    let previous = wgcli::private_key(&args.interface).await?;
+    let keypair = KeyPair::gen();
+
+    let mut client = connect(&args.server).await?;
+    let (nonce, proof) =
+        prove_possession(&mut client, &keypair.private, &args.server.account).await?;
+    // сервер отдает адреса новому ключу, только если клиент владеет и старым
+    let (previous_nonce, previous_proof) =
+        prove_possession(&mut client, &previous, &args.server.account).await?;
+    let request = ReserveIpRequest {
+        account: args.server.account.clone(),
+        public_key: keypair.public.into_base_64(),
+        nonce,
+        proof,
+        previous_public_key: wg::PublicKey::from(&previous).into_base_64(),
+        previous_nonce,
+        previous_proof,
+        ..Default::default()
+    };
+    client.reserve_ip(request).await?;
+    set_private_key(&keypair.private, &args.interface).await?;
+    println!("public key {}", keypair.public.into_base_64());
+
+    Ok(())
+}
+
+async fn info(args: &ServerArguments) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect(args).await?;
+    let info = client
+        .get_server_info(GetServerInfoRequest {})
+        .await?
+        .into_inner();
+    println!("public_key\t{}", info.public_key);
+    println!("endpoint\t{}", info.endpoint);
+    for pool in &info.pools {
+        println!(
+            "network\t{}\ttotal\t{}\tfree\t{}",
+            pool.network, pool.total, pool.free
+        );
+    }
+    println!("version\t{}", info.version);
+    println!("capabilities\t{}", info.capabilities.join(","));
+    Ok(())
+}
+
+pub async fn execute(args: &Arguments) -> Result<(), Box<dyn std::error::Error>> {
+    match (&args.command, &args.up) {
+        (Some(ClientCommand::Up(args)), _) | (None, Some(args)) => up(args).await,
+        (Some(ClientCommand::Down(args)), _) => down(args).await,
+        (Some(ClientCommand::Renew(args)), _) => renew(args).await,
+        (Some(ClientCommand::Rotate(args)), _) => rotate(args).await,
+        (Some(ClientCommand::Info(args)), _) => info(args).await,
+        (None, None) => unreachable!("clap requires either subcommand or up arguments"),
+    }
+}
@@ -0,0 +1,127 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use clap::Args;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use serde_with::{formats::CommaSeparator, serde_as, StringWithSeparator};
+
+use crate::common::{
+    config::CONFIG,
+    storage::{get_storage, new_preshared_key, to_wg_quick, PeerConfig, PeerInfo, WgConfigPeer},
+    wg::{FromBase64, PublicKey, SerdeBase64},
+};
+use crate::service::wireguard_add_peer;
+
+#[derive(Debug, Args)]
+pub struct Arguments {
+    #[clap(help = "account to register the peer under")]
+    pub account: String,
+    #[clap(
+        long,
+        help = "yaml with public_key and optional address, see example/add.yaml"
+    )]
+    pub file: Option<PathBuf>,
+    #[clap(long, help = "base64 public key of the peer, overrides the file")]
+    pub public_key: Option<String>,
+    #[clap(
+        long,
+        help = "address to assign instead of the first free one, overrides the file"
+    )]
+    pub address: Option<IpAddr>,
+}
+
+#[serde_as]
+#[derive(Deserialize, Default)]
+pub struct AddFile {
+    #[serde_as(as = "Option<SerdeBase64>")]
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
+    pub address: Option<IpAddr>,
+}
+
+// приватный ключ пира серверу неизвестен, администратор вписывает его сам
+#[serde_as]
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ClientInterface {
+    private_key: &'static str,
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, IpNet>")]
+    address: Vec<IpNet>,
+    #[serde(rename = "DNS", skip_serializing_if = "Vec::is_empty")]
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, IpAddr>")]
+    dns: Vec<IpAddr>,
+    #[serde(rename = "MTU", skip_serializing_if = "Option::is_none")]
+    mtu: Option<u16>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ClientConfigInterface {
+    interface: ClientInterface,
+}
+
+pub async fn execute(args: &Arguments) -> Result<String, Box<dyn std::error::Error>> {
+    let file: AddFile = match &args.file {
+        Some(path) => serde_yaml::from_str(&tokio::fs::read_to_string(path).await?)?,
+        None => AddFile::default(),
+    };
+    let public_key = match &args.public_key {
+        Some(key) => PublicKey::from_base_64(key)?,
+        None => file.public_key.ok_or("public key is required")?,
+    };
+    let address = args.address.or(file.address);
+
+    let mut storage = get_storage().await;
+    let peer = match storage.reserve(
+        &args.account,
+        public_key,
+        address.as_slice(),
+        None,
+        &[],
+        |address| PeerInfo {
+            preshared_key: new_preshared_key(),
+            ..PeerInfo::from(address)
+        },
+    ) {
+        Ok(peer) => peer,
+        // хранилище не менялось, а запись из Drop не успеет до выхода процесса
+        Err(error) => {
+            storage.discard().await;
+            return Err(error.into());
+        }
+    };
+    if let Err(error) = wireguard_add_peer(&public_key, &peer).await {
+        eprintln!(
+            "peer is saved, but interface is not updated ({}), it will be added by runserver",
+            error.message()
+        );
+    }
+
+    let interface = ClientConfigInterface {
+        interface: ClientInterface {
+            private_key: "<private key of the peer>",
+            address: storage.interface_addresses(&peer),
+            dns: CONFIG.client.dns.clone(),
+            mtu: CONFIG.client.mtu,
+        },
+    };
+    let server = PeerConfig {
+        public_key: &storage.server.public_key,
+        preshared_key: peer.preshared_key,
+        allowed_ips: [
+            CONFIG.client.allowed_ips(&storage.interface.addresses),
+            storage.advertised_routes(&public_key),
+        ]
+        .concat(),
+        endpoint: Some(&storage.server.endpoint),
+        persistent_keepalive: Some(CONFIG.client.persistent_keepalive).filter(|x| *x != 0),
+    };
+    let config = format!(
+        "{}\n{}",
+        to_wg_quick(&interface)?,
+        to_wg_quick(&WgConfigPeer::from(&server))?
+    );
+    storage.commit().await?;
+    Ok(config)
+}
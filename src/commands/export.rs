@@ -0,0 +1,27 @@
+use crate::common::storage::{
+    read_storage, to_wg_quick, PeerConfig, WgConfigInterface, WgConfigPeer,
+};
+
+pub async fn execute() -> Result<String, toml::ser::Error> {
+    let storage = read_storage().await;
+    let mut config = to_wg_quick(&WgConfigInterface::from(&storage.interface))?;
+
+    let mut accounts: Vec<_> = storage.peers.iter().collect();
+    accounts.sort_by_key(|(account, _)| *account);
+    for (account, peers) in accounts {
+        let mut peers: Vec<_> = peers.iter().collect();
+        peers.sort_by_key(|(_, info)| &info.addresses);
+        for (public_key, info) in peers {
+            let peer = PeerConfig {
+                public_key,
+                preshared_key: info.preshared_key,
+                allowed_ips: info.allowed_ips(),
+                endpoint: None,
+                persistent_keepalive: None,
+            };
+            config.push_str(&format!("\n# account: {}\n", account));
+            config.push_str(&to_wg_quick(&WgConfigPeer::from(&peer))?);
+        }
+    }
+    Ok(config)
+}
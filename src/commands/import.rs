@@ -0,0 +1,144 @@
+use std::path::PathBuf;
+
+use clap::Args;
+
+use crate::common::{
+    config::CONFIG,
+    storage::{check_host_bits, get_storage, PeerInfo},
+    wg::IntoBase64,
+    wgquick,
+};
+
+#[derive(Debug, Args)]
+pub struct Arguments {
+    #[clap(help = "account to register imported peers under")]
+    pub account: String,
+    #[clap(help = "wg-quick config or `wg showconf` output, '-' for stdin")]
+    pub file: PathBuf,
+}
+
+pub async fn execute(args: &Arguments) -> Result<(), Box<dyn std::error::Error>> {
+    let content = if args.file.as_os_str() == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        tokio::fs::read_to_string(&args.file).await?
+    };
+    let peers = wgquick::parse_peers(&content)?;
+
+    let mut storage = get_storage().await;
+    let (mut imported, mut conflicts) = (0, 0);
+    for peer in peers {
+        let key = peer.public_key.into_base_64();
+        if let Some(account) = storage.account_of(&peer.public_key) {
+            println!(
+                "conflict: peer {} is already registered for '{}'",
+                key, account
+            );
+            conflicts += 1;
+            continue;
+        }
+        // адреса пира - это адреса хостов из AllowedIPs, попадающие в пулы сервера
+        let addresses: Vec<_> = peer
+            .allowed_ips
+            .iter()
+            .filter(|net| net.prefix_len() == net.max_prefix_len())
+            .map(|net| net.addr())
+            .filter(|addr| storage.pools().any(|pool| pool.contains(addr)))
+            .collect();
+        if addresses.is_empty() {
+            let pools: Vec<_> = storage.pools().map(|pool| pool.to_string()).collect();
+            println!(
+                "conflict: peer {} has no host address inside {}",
+                key,
+                pools.join(",")
+            );
+            conflicts += 1;
+            continue;
+        }
+        // остальные AllowedIPs внутри пулов делегирования - делегированные пиру префиксы
+        let delegated: Vec<_> = peer
+            .allowed_ips
+            .iter()
+            .filter(|net| {
+                CONFIG
+                    .delegation_pools
+                    .iter()
+                    .any(|pool| pool.contains(*net))
+            })
+            .copied()
+            .collect();
+        // разрешенные account подсети вне пулов - объявленные пиром маршруты
+        let routes: Vec<_> = peer
+            .allowed_ips
+            .iter()
+            .filter(|net| {
+                !delegated.contains(net)
+                    && !storage
+                        .pools()
+                        .any(|pool| pool.contains(*net) || net.contains(&pool))
+                    && CONFIG.route_allowed(&args.account, net)
+            })
+            .copied()
+            .collect();
+        // остальное в AllowedIPs не сохраняется, администратор должен об этом узнать
+        for net in &peer.allowed_ips {
+            let used = addresses.contains(&net.addr()) && net.prefix_len() == net.max_prefix_len();
+            if !used && !delegated.contains(net) && !routes.contains(net) {
+                println!(
+                    "skipped: {} of peer {} is not a pool address, delegated prefix or allowed route",
+                    net, key
+                );
+            }
+        }
+        let mut conflicting = false;
+        for prefix in &delegated {
+            if let Err(error) = check_host_bits(prefix) {
+                println!("conflict: peer {}: {}", key, error);
+                conflicting = true;
+            }
+            if let Some(account) = storage.prefix_owner(prefix) {
+                println!(
+                    "conflict: prefix {} of peer {} overlaps a prefix of '{}'",
+                    prefix, key, account
+                );
+                conflicting = true;
+            }
+        }
+        if conflicting {
+            conflicts += 1;
+            continue;
+        }
+        // адреса и маршруты проверяются так же, как при add и ReserveIp,
+        // в пулах без адреса пир получает свободный
+        let info = match storage.reserve(
+            &args.account,
+            peer.public_key,
+            &addresses,
+            None,
+            &routes,
+            |addresses| PeerInfo {
+                preshared_key: peer.preshared_key,
+                ..PeerInfo::from(addresses)
+            },
+        ) {
+            Ok(info) => info,
+            Err(error) => {
+                println!("conflict: peer {}: {}", key, error);
+                conflicts += 1;
+                continue;
+            }
+        };
+        if let Some(info) = storage.get_mut(&args.account, &peer.public_key) {
+            info.delegated = delegated;
+        }
+        println!(
+            "imported peer {} with address {}",
+            key,
+            info.display_addresses()
+        );
+        imported += 1;
+    }
+    storage.commit().await?;
+    println!("{} peers imported, {} conflicts", imported, conflicts);
+    Ok(())
+}
@@ -1,20 +1,112 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 
-use crate::common::{config::CONFIG, storage::*, wg::KeyPair};
+use clap::Args;
+use ipnet::IpNet;
+use serde::Deserialize;
+use serde_with::{formats::PreferOne, serde_as, OneOrMany};
 
-pub async fn execute() -> std::result::Result<(), CommitError> {
-    let keypair = KeyPair::gen();
+use crate::common::{
+    config::{ConfigError, CONFIG},
+    custom::Endpoint,
+    storage::*,
+    wg::{KeyPair, PrivateKey, PublicKey, SerdeBase64},
+};
+
+#[derive(Debug, Args)]
+pub struct Arguments {
+    #[clap(
+        long,
+        help = "yaml with existing server keys, endpoint and address, see example/init.yaml"
+    )]
+    pub from: Option<PathBuf>,
+    #[clap(long, help = "overwrite existing storage")]
+    pub force: bool,
+}
+
+// все поля необязательны, недостающие берутся из конфигурации или генерируются
+#[serde_as]
+#[derive(Deserialize)]
+pub struct InitFile {
+    pub endpoint: Option<Endpoint>,
+    pub listen_port: Option<u16>,
+    #[serde_as(as = "Option<SerdeBase64>")]
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
+    #[serde_as(as = "Option<SerdeBase64>")]
+    #[serde(default)]
+    pub private_key: Option<PrivateKey>,
+    #[serde_as(as = "Option<OneOrMany<_, PreferOne>>")]
+    #[serde(default)]
+    pub address: Option<Vec<IpNet>>,
+    #[serde(default)]
+    pub save_config: bool,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum InitError {
+    #[error("storage {} already exists, use --force to overwrite it", .0.display())]
+    Exists(PathBuf),
+    #[error("cannot read init file: {}", .0)]
+    Read(#[from] std::io::Error),
+    #[error("cannot parse init file: {}", .0)]
+    Parse(#[from] serde_yaml::Error),
+    #[error("public_key is given without private_key")]
+    NoPrivateKey,
+    #[error("public_key does not match private_key")]
+    KeyMismatch,
+    #[error("config does not match server address: {}", .0)]
+    Config(#[from] ConfigError),
+    #[error("cannot save storage: {}", .0)]
+    Commit(#[from] CommitError),
+}
+
+fn keypair(init: &InitFile) -> Result<KeyPair, InitError> {
+    let Some(private) = init.private_key.clone() else {
+        return match init.public_key {
+            Some(_) => Err(InitError::NoPrivateKey),
+            None => Ok(KeyPair::gen()),
+        };
+    };
+    let public = PublicKey::from(&private);
+    if init.public_key.is_some_and(|key| key != public) {
+        return Err(InitError::KeyMismatch);
+    }
+    Ok(KeyPair { public, private })
+}
+
+pub async fn execute(args: &Arguments) -> std::result::Result<(), InitError> {
+    if !args.force && tokio::fs::try_exists(&CONFIG.storage).await? {
+        return Err(InitError::Exists(CONFIG.storage.clone()));
+    }
+    let init: InitFile = match &args.from {
+        Some(path) => serde_yaml::from_str(&tokio::fs::read_to_string(path).await?)?,
+        None => serde_yaml::from_str("{}")?,
+    };
+
+    let keypair = keypair(&init)?;
+    let addresses = init
+        .address
+        .unwrap_or_else(|| CONFIG.internal_address.clone());
+    CONFIG.validate(&addresses)?;
     let storage = Storage {
         interface: Interface {
-            listen_port: CONFIG.service.port.clone(),
+            listen_port: init.listen_port.unwrap_or(CONFIG.wgport),
             private_key: keypair.private,
-            address: CONFIG.internal_address.clone(),
+            addresses,
+            save_config: init.save_config,
         },
         server: ServerInfo {
             public_key: keypair.public,
-            endpoint: CONFIG.service.endpoint.clone(),
+            endpoint: init
+                .endpoint
+                .unwrap_or_else(|| CONFIG.service.endpoint.clone()),
         },
         peers: HashMap::default(),
+        draining: false,
+        index: None,
+        released: HashMap::default(),
+        account_pools: HashMap::default(),
     };
-    commit_storage(&storage).await
+    Ok(commit_storage(&storage).await?)
 }
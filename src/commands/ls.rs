@@ -1,6 +1,6 @@
-use crate::common::storage::get_storage;
+use crate::common::storage::read_storage;
 
 pub async fn execute() -> String {
-    let storage = get_storage().await;
+    let storage = read_storage().await;
     serde_yaml::to_string(&storage.peers).unwrap()
 }
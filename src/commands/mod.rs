@@ -1,3 +1,7 @@
+pub mod add;
+pub mod export;
+pub mod import;
 pub mod init;
 pub mod ls;
+pub mod revoke;
 pub mod run_server;
@@ -0,0 +1,87 @@
+use std::net::IpAddr;
+
+use clap::{ArgGroup, Args};
+
+use crate::common::{
+    config::CONFIG,
+    storage::{get_storage, read_storage, Storage},
+    wg::{FromBase64, IntoBase64, PublicKey},
+    wgcli,
+};
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("filter").required(true).multiple(true)))]
+pub struct Arguments {
+    #[clap(long, group = "filter", help = "revoke all peers of the account")]
+    pub account: Option<String>,
+    #[clap(
+        long,
+        group = "filter",
+        help = "revoke the peer with this base64 public key"
+    )]
+    pub public_key: Option<String>,
+    #[clap(
+        long,
+        group = "filter",
+        help = "revoke the peer with this internal address"
+    )]
+    pub address: Option<IpAddr>,
+    #[clap(long, help = "only show what would be removed")]
+    pub dry_run: bool,
+}
+
+// все заданные фильтры должны совпасть одновременно
+fn matching(
+    storage: &Storage,
+    args: &Arguments,
+    public_key: Option<PublicKey>,
+) -> Vec<(String, PublicKey, String)> {
+    storage
+        .peers
+        .iter()
+        .filter(|(account, _)| args.account.as_ref().is_none_or(|x| x == *account))
+        .flat_map(|(account, peers)| {
+            peers
+                .iter()
+                .filter(|(key, _)| public_key.is_none_or(|x| x == **key))
+                .filter(|(_, info)| args.address.is_none_or(|x| info.addresses.contains(&x)))
+                .map(|(key, info)| (account.clone(), *key, info.display_addresses()))
+        })
+        .collect()
+}
+
+pub async fn execute(args: &Arguments) -> Result<(), Box<dyn std::error::Error>> {
+    let public_key = args
+        .public_key
+        .as_deref()
+        .map(PublicKey::from_base_64)
+        .transpose()?;
+
+    // без изменений хранилище не блокируется и не перезаписывается
+    let matched = matching(&read_storage().await, args, public_key);
+    if matched.is_empty() {
+        return Err("no matching peers".into());
+    }
+    if args.dry_run {
+        for (account, key, addr) in matched {
+            println!("would revoke {} ({}) {}", key.into_base_64(), account, addr);
+        }
+        return Ok(());
+    }
+
+    let mut storage = get_storage().await;
+    // пока блокировка не была взята, хранилище могло измениться
+    for (account, key, addr) in matching(&storage, args, public_key) {
+        storage.remove(&account, &key);
+        if let Err(error) = wgcli::remove_peer(&CONFIG.interface, &key).await {
+            eprintln!(
+                "cannot remove {} from interface: {}",
+                key.into_base_64(),
+                error
+            );
+        }
+        println!("revoked {} ({}) {}", key.into_base_64(), account, addr);
+    }
+    storage.commit().await?;
+    Ok(())
+}
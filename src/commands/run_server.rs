@@ -1,12 +1,16 @@
-use crate::common::storage::get_storage;
-use crate::common::wg::{IntoBase64, PrivateKey};
+use crate::common::storage::{get_storage, Interface};
+use crate::common::wg::IntoBase64;
 use std::net::SocketAddr;
 use std::process::{ExitStatus, Stdio};
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
-use tonic::transport::Server;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
 
-use crate::{common::config::CONFIG, service};
+use crate::{
+    admin_service, auth,
+    common::config::{Tls, CONFIG},
+    leases, reconciler, service,
+};
 
 fn check(status: ExitStatus, error: &str) -> Result<(), Box<dyn std::error::Error>> {
     if !status.success() {
@@ -16,7 +20,7 @@ fn check(status: ExitStatus, error: &str) -> Result<(), Box<dyn std::error::Erro
 }
 
 pub async fn setup_wireguard_interface(
-    private_key: &PrivateKey,
+    interface: &Interface,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Создание интерфейса wg0
     check(
@@ -27,20 +31,22 @@ pub async fn setup_wireguard_interface(
         "Failed to add interface",
     )?;
 
-    // Назначение IP адреса интерфейсу wg0
-    check(
-        Command::new("ip")
-            .args([
-                "address",
-                "add",
-                &CONFIG.internal_address.to_string(),
-                "dev",
-                &CONFIG.interface,
-            ])
-            .status()
-            .await?,
-        "Failed to set ip to interface",
-    )?;
+    // Назначение IP адресов интерфейсу wg0, по одному на каждый пул
+    for address in &interface.addresses {
+        check(
+            Command::new("ip")
+                .args([
+                    "address",
+                    "add",
+                    &address.to_string(),
+                    "dev",
+                    &CONFIG.interface,
+                ])
+                .status()
+                .await?,
+            "Failed to set ip to interface",
+        )?;
+    }
     // Настройка приватного ключа через /dev/stdin
     let mut child = Command::new("wg")
         .args([
@@ -49,14 +55,14 @@ pub async fn setup_wireguard_interface(
             "private-key",
             "/dev/stdin",
             "listen-port",
-            &CONFIG.wgport.to_string(),
+            &interface.listen_port.to_string(),
         ])
         .stdin(Stdio::piped())
         .spawn()?;
 
     let stdin = child.stdin.as_mut().ok_or("Failed to open stdin")?;
     stdin
-        .write_all(private_key.into_base_64().as_bytes())
+        .write_all(interface.private_key.into_base_64().as_bytes())
         .await?;
     stdin.flush().await?;
     let status = child.wait().await?;
@@ -74,19 +80,69 @@ pub async fn setup_wireguard_interface(
         "Failed to set private key",
     )?;
 
+    // делегированные префиксы маршрутизируются через интерфейс, дальше пира выбирает wg
+    for pool in &CONFIG.delegation_pools {
+        check(
+            Command::new("ip")
+                .args([
+                    "route",
+                    "replace",
+                    &pool.to_string(),
+                    "dev",
+                    &CONFIG.interface,
+                ])
+                .status()
+                .await?,
+            "Failed to add route to delegation pool",
+        )?;
+    }
+
     Ok(())
 }
 
+async fn tls_config(tls: &Tls) -> Result<ServerTlsConfig, std::io::Error> {
+    let cert = tokio::fs::read(&tls.cert).await?;
+    let key = tokio::fs::read(&tls.key).await?;
+    let mut config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+    if let Some(client_ca) = &tls.client_ca {
+        let client_ca = tokio::fs::read(client_ca).await?;
+        config = config.client_ca_root(Certificate::from_pem(client_ca));
+    }
+    Ok(config)
+}
+
 pub async fn execute() -> Result<(), Box<dyn std::error::Error>> {
     let addr = SocketAddr::new(CONFIG.service.address, CONFIG.service.port);
 
     {
         let storage = get_storage().await;
-        setup_wireguard_interface(&storage.interface.private_key).await?;
+        CONFIG.validate(&storage.interface.addresses)?;
+        setup_wireguard_interface(&storage.interface).await?;
     }
+    // восстанавливаем пиры из хранилища, дальше следим за расхождениями в фоне
+    reconciler::reconcile().await?;
+    tokio::spawn(reconciler::run());
+    tokio::spawn(leases::run());
+
     let service = service::ServiceImpl {};
-    Server::builder()
-        .add_service(service::DhcServiceServer::new(service))
+    let interceptor = auth::AuthInterceptor::from_config()?;
+    let mut server = Server::builder();
+    if let Some(tls) = &CONFIG.service.tls {
+        server = server.tls_config(tls_config(tls).await?)?;
+    }
+    // AdminService поднимается, только если настроены учетные записи администраторов
+    let admin = (!CONFIG.admin.is_empty()).then(|| {
+        admin_service::AdminServiceServer::with_interceptor(
+            admin_service::AdminServiceImpl {},
+            auth::AdminInterceptor,
+        )
+    });
+    server
+        .add_service(service::DhcServiceServer::with_interceptor(
+            service,
+            interceptor,
+        ))
+        .add_optional_service(admin)
         .serve(addr)
         .await?;
     Ok(())
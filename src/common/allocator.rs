@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::IpNet;
+
+// свободные адреса пула в виде непересекающихся интервалов [start, end],
+// адреса хранятся числами, чтобы ipv4 и ipv6 обрабатывались одинаково
+pub struct AddressIndex {
+    pool: IpNet,
+    free: BTreeMap<u128, u128>,
+}
+
+fn to_number(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(addr) => u128::from(u32::from(addr)),
+        IpAddr::V6(addr) => u128::from(addr),
+    }
+}
+
+fn from_number(number: u128, ipv4: bool) -> IpAddr {
+    match ipv4 {
+        true => IpAddr::V4(Ipv4Addr::from(number as u32)),
+        false => IpAddr::V6(Ipv6Addr::from(number)),
+    }
+}
+
+// первая подсеть длины prefix_len в pool, не пересекающаяся с occupied:
+// занятые сети обходятся по возрастанию начала, и кандидат сдвигается
+// за каждую пересекающуюся с ним, поэтому хватает одного прохода
+pub fn first_free_prefix(pool: &IpNet, prefix_len: u8, occupied: &[IpNet]) -> Option<IpNet> {
+    let ipv4 = pool.addr().is_ipv4();
+    let size = 1u128.checked_shl(u32::from(pool.max_prefix_len() - prefix_len));
+    let last = to_number(pool.broadcast());
+    let mut intervals: Vec<_> = occupied
+        .iter()
+        .filter(|net| net.addr().is_ipv4() == ipv4)
+        .map(|net| (to_number(net.network()), to_number(net.broadcast())))
+        .collect();
+    intervals.sort_unstable();
+    let mut candidate = to_number(pool.network());
+    for (start, end) in intervals {
+        // без size подсеть - все адресное пространство
+        let candidate_end = size.map_or(u128::MAX, |size| candidate + (size - 1));
+        if end < candidate {
+            continue;
+        }
+        if start > candidate_end {
+            break;
+        }
+        let size = size?;
+        candidate = end.checked_add(1)?.checked_next_multiple_of(size)?;
+        if candidate > last {
+            return None;
+        }
+    }
+    let candidate_end = size.map_or(u128::MAX, |size| candidate + (size - 1));
+    if candidate_end > last {
+        return None;
+    }
+    IpNet::new(from_number(candidate, ipv4), prefix_len).ok()
+}
+
+impl AddressIndex {
+    // изначально свободны все адреса хостов пула
+    pub fn new(pool: IpNet) -> Self {
+        let mut start = to_number(pool.network());
+        let mut end = to_number(pool.broadcast());
+        // те же исключения, что и в storage::is_host
+        match pool {
+            IpNet::V4(net) if net.prefix_len() < 31 => {
+                start += 1;
+                end -= 1;
+            }
+            IpNet::V6(net) if net.prefix_len() < 127 => start += 1,
+            _ => {}
+        }
+        AddressIndex {
+            pool,
+            free: BTreeMap::from([(start, end)]),
+        }
+    }
+    pub fn pool(&self) -> IpNet {
+        self.pool
+    }
+    // число свободных адресов, для ::/0 ограничено u128::MAX
+    pub fn free_count(&self) -> u128 {
+        self.free.iter().fold(0u128, |count, (start, end)| {
+            count.saturating_add(end - start).saturating_add(1)
+        })
+    }
+    fn to_addr(&self, number: u128) -> IpAddr {
+        from_number(number, self.pool.addr().is_ipv4())
+    }
+    fn contains(&self, number: u128) -> bool {
+        self.free
+            .range(..=number)
+            .next_back()
+            .is_some_and(|(_, end)| *end >= number)
+    }
+    fn first_free_between(&self, low: u128, high: u128) -> Option<u128> {
+        let found = match self.free.range(..=low).next_back() {
+            Some((_, end)) if *end >= low => Some(low),
+            _ => self.free.range(low..).next().map(|(start, _)| *start),
+        };
+        found.filter(|number| *number <= high)
+    }
+    // первый свободный адрес сети net не раньше offset от ее начала, поиск идет по кругу,
+    // net - весь пул или его часть
+    pub fn free_in(&self, net: &IpNet, offset: u128) -> Option<IpAddr> {
+        if net.addr().is_ipv4() != self.pool.addr().is_ipv4() {
+            return None;
+        }
+        let low = to_number(net.network()).max(to_number(self.pool.network()));
+        let high = to_number(net.broadcast()).min(to_number(self.pool.broadcast()));
+        if low > high {
+            return None;
+        }
+        let from = low
+            + match (high - low).checked_add(1) {
+                Some(size) => offset % size,
+                None => offset,
+            };
+        self.first_free_between(from, high)
+            .or_else(|| self.first_free_between(low, high))
+            .map(|number| self.to_addr(number))
+    }
+    fn remove_range(&mut self, low: u128, high: u128) {
+        // интервалы упорядочены и не пересекаются, поэтому их концы тоже упорядочены
+        let overlapping: Vec<_> = self
+            .free
+            .range(..=high)
+            .rev()
+            .take_while(|(_, end)| **end >= low)
+            .map(|(start, end)| (*start, *end))
+            .collect();
+        for (start, end) in overlapping {
+            self.free.remove(&start);
+            if start < low {
+                self.free.insert(start, low - 1);
+            }
+            if end > high {
+                self.free.insert(high + 1, end);
+            }
+        }
+    }
+    // адрес занят пиром или не должен выдаваться, адреса вне пула игнорируются
+    pub fn take(&mut self, addr: IpAddr) {
+        if self.pool.contains(&addr) {
+            let number = to_number(addr);
+            self.remove_range(number, number);
+        }
+    }
+    pub fn take_net(&mut self, net: &IpNet) {
+        let low = to_number(net.network()).max(to_number(self.pool.network()));
+        let high = to_number(net.broadcast()).min(to_number(self.pool.broadcast()));
+        if net.addr().is_ipv4() == self.pool.addr().is_ipv4() && low <= high {
+            self.remove_range(low, high);
+        }
+    }
+    // вызывающий отвечает за то, что адрес является адресом хоста и может выдаваться
+    pub fn release(&mut self, addr: IpAddr) {
+        if !self.pool.contains(&addr) || self.contains(to_number(addr)) {
+            return;
+        }
+        let number = to_number(addr);
+        let (mut start, mut end) = (number, number);
+        if let Some((&prev_start, &prev_end)) = self.free.range(..number).next_back() {
+            if prev_end + 1 == number {
+                start = prev_start;
+                self.free.remove(&prev_start);
+            }
+        }
+        if let Some(next) = number.checked_add(1) {
+            if let Some(next_end) = self.free.remove(&next) {
+                end = next_end;
+            }
+        }
+        self.free.insert(start, end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn intervals(index: &AddressIndex) -> Vec<(IpAddr, IpAddr)> {
+        index
+            .free
+            .iter()
+            .map(|(start, end)| (index.to_addr(*start), index.to_addr(*end)))
+            .collect()
+    }
+
+    #[test]
+    fn new_skips_network_and_broadcast() {
+        let index = AddressIndex::new(net("10.0.0.0/24"));
+        assert_eq!(intervals(&index), [(addr("10.0.0.1"), addr("10.0.0.254"))]);
+        let index = AddressIndex::new(net("fd00::/64"));
+        assert_eq!(
+            intervals(&index),
+            [(addr("fd00::1"), addr("fd00::ffff:ffff:ffff:ffff"))]
+        );
+    }
+
+    #[test]
+    fn point_to_point_pools_use_both_addresses() {
+        let index = AddressIndex::new(net("10.0.0.0/31"));
+        assert_eq!(intervals(&index), [(addr("10.0.0.0"), addr("10.0.0.1"))]);
+        let index = AddressIndex::new(net("fd00::/127"));
+        assert_eq!(intervals(&index), [(addr("fd00::"), addr("fd00::1"))]);
+        let index = AddressIndex::new(net("10.0.0.7/32"));
+        assert_eq!(intervals(&index), [(addr("10.0.0.7"), addr("10.0.0.7"))]);
+    }
+
+    #[test]
+    fn take_splits_and_release_merges() {
+        let mut index = AddressIndex::new(net("10.0.0.0/29"));
+        index.take(addr("10.0.0.3"));
+        index.take(addr("10.0.0.4"));
+        assert_eq!(
+            intervals(&index),
+            [
+                (addr("10.0.0.1"), addr("10.0.0.2")),
+                (addr("10.0.0.5"), addr("10.0.0.6"))
+            ]
+        );
+
+        // сливается только с предыдущим интервалом
+        index.release(addr("10.0.0.3"));
+        assert_eq!(
+            intervals(&index),
+            [
+                (addr("10.0.0.1"), addr("10.0.0.3")),
+                (addr("10.0.0.5"), addr("10.0.0.6"))
+            ]
+        );
+        // с обоими соседями
+        index.release(addr("10.0.0.4"));
+        assert_eq!(intervals(&index), [(addr("10.0.0.1"), addr("10.0.0.6"))]);
+    }
+
+    #[test]
+    fn release_merges_with_next_and_keeps_isolated() {
+        let mut index = AddressIndex::new(net("10.0.0.0/29"));
+        for host in 1..=6 {
+            index.take(addr(&format!("10.0.0.{host}")));
+        }
+        assert!(intervals(&index).is_empty());
+        index.release(addr("10.0.0.5"));
+        index.release(addr("10.0.0.2"));
+        assert_eq!(
+            intervals(&index),
+            [
+                (addr("10.0.0.2"), addr("10.0.0.2")),
+                (addr("10.0.0.5"), addr("10.0.0.5"))
+            ]
+        );
+        index.release(addr("10.0.0.4"));
+        assert_eq!(
+            intervals(&index),
+            [
+                (addr("10.0.0.2"), addr("10.0.0.2")),
+                (addr("10.0.0.4"), addr("10.0.0.5"))
+            ]
+        );
+    }
+
+    #[test]
+    fn release_of_free_or_foreign_address_is_ignored() {
+        let mut index = AddressIndex::new(net("10.0.0.0/29"));
+        index.release(addr("10.0.0.3"));
+        index.release(addr("10.0.1.3"));
+        index.release(addr("fd00::3"));
+        assert_eq!(intervals(&index), [(addr("10.0.0.1"), addr("10.0.0.6"))]);
+    }
+
+    #[test]
+    fn take_net_removes_overlapping_intervals() {
+        let mut index = AddressIndex::new(net("10.0.0.0/28"));
+        index.take(addr("10.0.0.5"));
+        index.take(addr("10.0.0.9"));
+        index.take_net(&net("10.0.0.4/30"));
+        index.take_net(&net("10.0.0.8/30"));
+        assert_eq!(
+            intervals(&index),
+            [
+                (addr("10.0.0.1"), addr("10.0.0.3")),
+                (addr("10.0.0.12"), addr("10.0.0.14"))
+            ]
+        );
+        // сети другого семейства и вне пула не трогают индекс
+        index.take_net(&net("fd00::/64"));
+        index.take_net(&net("10.0.1.0/24"));
+        assert_eq!(intervals(&index).len(), 2);
+        index.take_net(&net("10.0.0.0/8"));
+        assert!(intervals(&index).is_empty());
+    }
+
+    #[test]
+    fn free_in_wraps_around_the_pool_end() {
+        let pool = net("10.0.0.0/29");
+        let mut index = AddressIndex::new(pool);
+        assert_eq!(index.free_in(&pool, 0), Some(addr("10.0.0.1")));
+        assert_eq!(index.free_in(&pool, 5), Some(addr("10.0.0.5")));
+        // смещение больше размера пула берется по модулю
+        assert_eq!(index.free_in(&pool, 8 + 3), Some(addr("10.0.0.3")));
+        // после смещения свободных нет, поиск продолжается с начала
+        index.take(addr("10.0.0.5"));
+        index.take(addr("10.0.0.6"));
+        assert_eq!(index.free_in(&pool, 5), Some(addr("10.0.0.1")));
+        // широковещательный адрес не выдается даже при смещении на него
+        assert_eq!(index.free_in(&pool, 7), Some(addr("10.0.0.1")));
+        for host in 1..=4 {
+            index.take(addr(&format!("10.0.0.{host}")));
+        }
+        assert_eq!(index.free_in(&pool, 0), None);
+    }
+
+    #[test]
+    fn free_in_searches_only_inside_the_subnet() {
+        let pool = net("10.0.0.0/24");
+        let mut index = AddressIndex::new(pool);
+        let subnet = net("10.0.0.16/28");
+        assert_eq!(index.free_in(&subnet, 0), Some(addr("10.0.0.16")));
+        index.take_net(&net("10.0.0.16/29"));
+        assert_eq!(index.free_in(&subnet, 0), Some(addr("10.0.0.24")));
+        assert_eq!(index.free_in(&subnet, 15), Some(addr("10.0.0.31")));
+        index.take(addr("10.0.0.31"));
+        assert_eq!(index.free_in(&subnet, 15), Some(addr("10.0.0.24")));
+        index.take_net(&subnet);
+        assert_eq!(index.free_in(&subnet, 0), None);
+        assert_eq!(index.free_in(&net("fd00::/64"), 0), None);
+        assert_eq!(index.free_in(&net("10.0.1.0/24"), 0), None);
+    }
+
+    #[test]
+    fn ipv6_pool_end_wraps_without_overflow() {
+        let pool = net("::/0");
+        let mut index = AddressIndex::new(pool);
+        assert_eq!(
+            index.free_in(&pool, u128::MAX),
+            Some(addr("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"))
+        );
+        index.take(addr("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+        assert_eq!(index.free_in(&pool, u128::MAX), Some(addr("::1")));
+        index.release(addr("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+        assert_eq!(
+            intervals(&index),
+            [(addr("::1"), addr("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"))]
+        );
+    }
+
+    #[test]
+    fn first_free_prefix_skips_overlapping_prefixes() {
+        let pool = net("10.12.0.0/24");
+        assert_eq!(first_free_prefix(&pool, 29, &[]), Some(net("10.12.0.0/29")));
+        // занятые сети в любом порядке, в том числе шире и уже искомой
+        let occupied = [
+            net("10.12.0.8/30"),
+            net("10.12.0.0/29"),
+            net("fd00::/64"),
+            net("10.12.0.17/32"),
+        ];
+        assert_eq!(
+            first_free_prefix(&pool, 29, &occupied),
+            Some(net("10.12.0.24/29"))
+        );
+        assert_eq!(
+            first_free_prefix(&pool, 28, &occupied),
+            Some(net("10.12.0.32/28"))
+        );
+        assert_eq!(first_free_prefix(&pool, 25, &[net("10.12.0.0/16")]), None);
+        assert_eq!(
+            first_free_prefix(&pool, 25, &[net("10.12.0.0/25")]),
+            Some(net("10.12.0.128/25"))
+        );
+        assert_eq!(first_free_prefix(&pool, 24, &[net("10.12.0.255/32")]), None);
+    }
+
+    #[test]
+    fn first_free_prefix_at_the_end_of_address_space() {
+        let pool = net("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ff00/120");
+        let occupied = [net("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ff00/121")];
+        assert_eq!(
+            first_free_prefix(&pool, 121, &occupied),
+            Some(net("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ff80/121"))
+        );
+        let occupied = [net("ffff:ffff:ffff:ffff:ffff:ffff:ffff:fff0/124")];
+        assert_eq!(
+            first_free_prefix(&pool, 121, &occupied),
+            Some(pool.subnets(121).unwrap().next().unwrap())
+        );
+        let full = [pool];
+        assert_eq!(first_free_prefix(&pool, 128, &full), None);
+        assert_eq!(first_free_prefix(&net("::/0"), 0, &[]), Some(net("::/0")));
+        assert_eq!(first_free_prefix(&net("::/0"), 0, &[net("::1/128")]), None);
+    }
+}
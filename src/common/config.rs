@@ -1,15 +1,20 @@
-use crate::common::custom::Endpoint;
+use crate::common::custom::{Endpoint, HostOrNet};
+use crate::common::storage::is_host;
+use crate::common::wg::{PublicKey, SerdeBase64};
 use ipnet::IpNet;
+#[cfg(not(test))]
 use lazy_static::lazy_static;
 use serde::Deserialize;
+use serde_with::{formats::PreferOne, serde_as, OneOrMany};
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::path::PathBuf;
 
 fn default_addr() -> IpAddr {
     "0.0.0.0".parse().unwrap()
 }
-fn default_internal_addr() -> IpNet {
-    "10.11.0.1/16".parse().unwrap()
+fn default_internal_addr() -> Vec<IpNet> {
+    vec!["10.11.0.1/16".parse().unwrap()]
 }
 fn default_port() -> u16 {
     5010
@@ -17,6 +22,36 @@ fn default_port() -> u16 {
 fn default_wireguard_port() -> u16 {
     55000
 }
+fn default_reconcile_interval() -> u64 {
+    30
+}
+fn default_reap_interval() -> u64 {
+    60
+}
+fn default_offer_timeout() -> u64 {
+    60
+}
+fn default_persistent_keepalive() -> u16 {
+    5
+}
+fn default_quarantine() -> u64 {
+    3600
+}
+fn default_ipv4_prefix_len() -> u8 {
+    28
+}
+fn default_ipv6_prefix_len() -> u8 {
+    120
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // CA клиентских сертификатов, с ним включается mTLS
+    // и account берется из CN сертификата клиента
+    pub client_ca: Option<PathBuf>,
+}
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Service {
@@ -25,19 +60,326 @@ pub struct Service {
     #[serde(default = "default_port")]
     pub port: u16,
     pub endpoint: Endpoint,
+    pub tls: Option<Tls>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Auth {
+    // постоянные токены, account -> token
+    Tokens { tokens: HashMap<String, String> },
+    // файл в формате htpasswd с bcrypt хешами паролей
+    Htpasswd { file: PathBuf },
+}
+
+// настройки, которые сервер отдает клиентам вместе с адресом
+#[derive(Deserialize, Clone, Debug)]
+pub struct ClientSettings {
+    // AllowedIPs сервера у клиента, пусто - только внутренняя сеть,
+    // 0.0.0.0/0 - весь трафик клиента идет через сервер
+    #[serde(default)]
+    pub allowed_ips: Vec<IpNet>,
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    pub mtu: Option<u16>,
+    // 0 отключает keepalive
+    #[serde(default = "default_persistent_keepalive")]
+    pub persistent_keepalive: u16,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        ClientSettings {
+            allowed_ips: vec![],
+            dns: vec![],
+            mtu: None,
+            persistent_keepalive: default_persistent_keepalive(),
+        }
+    }
+}
+
+impl ClientSettings {
+    pub fn allowed_ips(&self, networks: &[IpNet]) -> Vec<IpNet> {
+        if self.allowed_ips.is_empty() {
+            networks.iter().map(IpNet::trunc).collect()
+        } else {
+            self.allowed_ips.clone()
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HashBy {
+    #[default]
+    PublicKey,
+    Account,
+}
+
+// как выбирается адрес из свободных, если он не запрошен и не закреплен
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum Allocation {
+    // первый свободный адрес пула
+    #[default]
+    Sequential,
+    Random,
+    // один и тот же ключ или account получает один и тот же адрес, пока он свободен
+    Hash {
+        #[serde(default)]
+        by: HashBy,
+    },
+    // сначала адреса, которые еще никому не выдавались, затем дольше всех свободные,
+    // освобожденный адрес не выдается раньше чем через quarantine секунд
+    LeastRecentlyReleased {
+        #[serde(default = "default_quarantine")]
+        quarantine: u64,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WhenFull {
+    // новые пиры account получают ошибку, пока в его подсети нет свободных адресов
+    #[default]
+    Reject,
+    // account получает еще одну подсеть
+    Extend,
 }
 
+// каждый account получает свою подсеть пула при первом резервировании,
+// дальше его пиры получают адреса только из нее
+#[derive(Deserialize, Clone, Debug)]
+pub struct AccountPools {
+    #[serde(default = "default_ipv4_prefix_len")]
+    pub ipv4_prefix_len: u8,
+    #[serde(default = "default_ipv6_prefix_len")]
+    pub ipv6_prefix_len: u8,
+    #[serde(default)]
+    pub when_full: WhenFull,
+}
+
+impl AccountPools {
+    pub fn prefix_len(&self, pool: &IpNet) -> u8 {
+        match pool {
+            IpNet::V4(_) => self.ipv4_prefix_len,
+            IpNet::V6(_) => self.ipv6_prefix_len,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AdminCredential {
+    pub token: String,
+    pub role: Role,
+}
+
+// закрепленный за пиром адрес, динамически он никому не выдается
+#[serde_as]
+#[derive(Deserialize, Clone)]
+pub struct StaticAssignment {
+    pub account: String,
+    // без ключа адрес получает любой пир account, но только один
+    #[serde_as(as = "Option<SerdeBase64>")]
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
+    // по одному адресу на пул
+    #[serde_as(as = "OneOrMany<_, PreferOne>")]
+    pub address: Vec<IpAddr>,
+}
+
+#[serde_as]
 #[derive(Deserialize)]
 pub struct Config {
     pub service: Service,
     pub storage: PathBuf,
     pub interface: String,
+    // один или несколько пулов, например ipv4 и ipv6, пир получает адрес из каждого
+    #[serde_as(as = "OneOrMany<_, PreferOne>")]
     #[serde(default = "default_internal_addr")]
-    pub internal_address: IpNet,
+    pub internal_address: Vec<IpNet>,
     #[serde(default = "default_wireguard_port")]
     pub wgport: u16,
+    // период сверки интерфейса с хранилищем в секундах
+    #[serde(default = "default_reconcile_interval")]
+    pub reconcile_interval: u64,
+    // время аренды адреса в секундах, без него адреса выдаются навсегда
+    pub lease_duration: Option<u64>,
+    // период удаления пиров с истекшей арендой в секундах
+    #[serde(default = "default_reap_interval")]
+    pub reap_interval: u64,
+    // сколько секунд предложенный адрес ждет подтверждения
+    #[serde(default = "default_offer_timeout")]
+    pub offer_timeout: u64,
+    // без этой секции любой может резервировать адреса под любым account
+    pub auth: Option<Auth>,
+    // учетные записи AdminService, без них сервис не запускается
+    #[serde(default)]
+    pub admin: HashMap<String, AdminCredential>,
+    #[serde(default)]
+    pub client: ClientSettings,
+    // выдавать каждому пиру свой preshared key
+    #[serde(default)]
+    pub preshared_keys: bool,
+    #[serde(default)]
+    pub static_assignments: Vec<StaticAssignment>,
+    // адреса и сети, которые никогда не выдаются пирам
+    #[serde_as(as = "Vec<HostOrNet>")]
+    #[serde(default)]
+    pub exclude: Vec<IpNet>,
+    // адреса и сети, которые выдаются только через static_assignments
+    #[serde_as(as = "Vec<HostOrNet>")]
+    #[serde(default)]
+    pub reserved: Vec<IpNet>,
+    #[serde(default)]
+    pub allocation: Allocation,
+    // без этой секции адреса выдаются из всего пула
+    pub account_pools: Option<AccountPools>,
+    // сети, из которых пирам по запросу делегируются маршрутизируемые префиксы
+    #[serde(default)]
+    pub delegation_pools: Vec<IpNet>,
+    // account -> подсети, которые его пиры могут объявлять как сети за собой
+    #[serde(default)]
+    pub advertised_routes: HashMap<String, Vec<IpNet>>,
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("server address {} is in exclude", .0)]
+    ServerExcluded(IpAddr),
+    #[error("static address {} is not a host address of any pool", .0)]
+    StaticOutOfPool(IpAddr),
+    #[error("static address {} is in exclude", .0)]
+    StaticExcluded(IpAddr),
+    #[error("static address {} is the server address", .0)]
+    StaticIsServer(IpAddr),
+    #[error("static address {} is assigned more than once", .0)]
+    StaticDuplicate(IpAddr),
+    #[error("account pool prefix length {} does not fit into {}", .1, .0)]
+    AccountPrefix(IpNet, u8),
+    #[error("delegation pool {} overlaps address pool {}", .0, .1)]
+    DelegationOverlap(IpNet, IpNet),
+    #[error("delegation pools {} and {} overlap", .0, .1)]
+    DelegationPoolsOverlap(IpNet, IpNet),
+    #[error("delegation pool {} overlaps excluded {}", .0, .1)]
+    DelegationExcluded(IpNet, IpNet),
+    #[error("advertised route {} of '{}' overlaps pool {}", .1, .0, .2)]
+    RouteOverlap(String, IpNet, IpNet),
+}
+
+impl Config {
+    // назначение по ключу важнее назначения на весь account
+    pub fn static_assignment(
+        &self,
+        account: &str,
+        public_key: &PublicKey,
+    ) -> Option<&StaticAssignment> {
+        let of_account = || {
+            self.static_assignments
+                .iter()
+                .filter(move |assignment| assignment.account == account)
+        };
+        of_account()
+            .find(|assignment| assignment.public_key.as_ref() == Some(public_key))
+            .or_else(|| of_account().find(|assignment| assignment.public_key.is_none()))
+    }
+    pub fn is_static(&self, addr: &IpAddr) -> bool {
+        self.static_assignments
+            .iter()
+            .any(|assignment| assignment.address.contains(addr))
+    }
+    pub fn route_allowed(&self, account: &str, route: &IpNet) -> bool {
+        self.advertised_routes
+            .get(account)
+            .is_some_and(|allowed| allowed.iter().any(|net| net.contains(route)))
+    }
+    pub fn is_excluded(&self, addr: &IpAddr) -> bool {
+        self.exclude.iter().any(|net| net.contains(addr))
+    }
+    pub fn is_reserved(&self, addr: &IpAddr) -> bool {
+        self.reserved.iter().any(|net| net.contains(addr))
+    }
+    // addresses - адреса сервера, они же задают пулы
+    pub fn validate(&self, addresses: &[IpNet]) -> Result<(), ConfigError> {
+        for address in addresses {
+            if self.is_excluded(&address.addr()) {
+                return Err(ConfigError::ServerExcluded(address.addr()));
+            }
+        }
+        if let Some(account_pools) = &self.account_pools {
+            for pool in addresses.iter().map(IpNet::trunc) {
+                let prefix_len = account_pools.prefix_len(&pool);
+                if prefix_len < pool.prefix_len() || prefix_len > pool.max_prefix_len() {
+                    return Err(ConfigError::AccountPrefix(pool, prefix_len));
+                }
+            }
+        }
+        let overlap = |a: &IpNet, b: &IpNet| a.contains(b) || b.contains(a);
+        for (i, delegation_pool) in self.delegation_pools.iter().enumerate() {
+            for pool in addresses.iter().map(IpNet::trunc) {
+                if overlap(&pool, delegation_pool) {
+                    return Err(ConfigError::DelegationOverlap(*delegation_pool, pool));
+                }
+            }
+            for other in &self.delegation_pools[i + 1..] {
+                if overlap(other, delegation_pool) {
+                    return Err(ConfigError::DelegationPoolsOverlap(
+                        *delegation_pool,
+                        *other,
+                    ));
+                }
+            }
+            // исключенные адреса и сети не учитываются при делегировании
+            for excluded in &self.exclude {
+                if overlap(excluded, delegation_pool) {
+                    return Err(ConfigError::DelegationExcluded(*delegation_pool, *excluded));
+                }
+            }
+        }
+        // маршрут поверх пула перебил бы маршрут интерфейса к нему
+        for (account, routes) in &self.advertised_routes {
+            for route in routes {
+                let pools = addresses.iter().map(IpNet::trunc);
+                if let Some(pool) = pools
+                    .chain(self.delegation_pools.iter().copied())
+                    .find(|pool| overlap(pool, route))
+                {
+                    return Err(ConfigError::RouteOverlap(account.clone(), *route, pool));
+                }
+            }
+        }
+        let mut seen = vec![];
+        for addr in self
+            .static_assignments
+            .iter()
+            .flat_map(|assignment| &assignment.address)
+        {
+            if !addresses.iter().any(|net| is_host(&net.trunc(), *addr)) {
+                return Err(ConfigError::StaticOutOfPool(*addr));
+            }
+            if self.is_excluded(addr) {
+                return Err(ConfigError::StaticExcluded(*addr));
+            }
+            if addresses.iter().any(|net| net.addr() == *addr) {
+                return Err(ConfigError::StaticIsServer(*addr));
+            }
+            if seen.contains(addr) {
+                return Err(ConfigError::StaticDuplicate(*addr));
+            }
+            seen.push(*addr);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(test))]
 fn get_config() -> Config {
     let config = shellexpand::tilde("~/.config/wgdhc.yaml").to_string();
     let file = std::fs::File::open(&config).unwrap();
@@ -46,6 +388,36 @@ fn get_config() -> Config {
         .unwrap()
 }
 
+#[cfg(not(test))]
 lazy_static! {
     pub static ref CONFIG: Config = get_config();
 }
+
+// в тестах у каждого потока своя конфигурация, ее задает use_config
+#[cfg(test)]
+pub struct TestConfig;
+
+#[cfg(test)]
+thread_local! {
+    static TEST_CONFIG: std::cell::Cell<Option<&'static Config>> = const { std::cell::Cell::new(None) };
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestConfig {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        TEST_CONFIG
+            .with(std::cell::Cell::get)
+            .expect("use_config is not called in the test")
+    }
+}
+
+#[cfg(test)]
+pub static CONFIG: TestConfig = TestConfig;
+
+#[cfg(test)]
+pub fn use_config(yaml: &str) {
+    let config: Config = serde_yaml::from_str(yaml).unwrap();
+    TEST_CONFIG.with(|cell| cell.set(Some(Box::leak(Box::new(config)))));
+}
@@ -1,6 +1,9 @@
+use std::net::IpAddr;
 use std::str::FromStr;
 
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use serde_with::DeserializeAs;
 use url::{Host, ParseError};
 
 #[derive(Clone, Debug)]
@@ -24,10 +27,7 @@ impl FromStr for Endpoint {
             .transpose()
             .map_err(|_| ParseError::InvalidPort)?;
 
-        Ok(Endpoint {
-            host: host,
-            port: port,
-        })
+        Ok(Endpoint { host, port })
     }
 }
 
@@ -62,3 +62,24 @@ impl<'de> Deserialize<'de> for Endpoint {
         Self::from_str(&string).map_err(gen_error)
     }
 }
+
+// адрес или сеть, одиночный адрес становится сетью из одного хоста
+pub struct HostOrNet;
+
+impl<'de> DeserializeAs<'de, IpNet> for HostOrNet {
+    fn deserialize_as<D>(deserializer: D) -> Result<IpNet, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let string = String::deserialize(deserializer)?;
+        let gen_error = || D::Error::custom(format!("cannot parse address or network '{string}'"));
+        match string.parse::<IpNet>() {
+            Ok(net) => Ok(net),
+            Err(_) => string
+                .parse::<IpAddr>()
+                .map(IpNet::from)
+                .map_err(|_| gen_error()),
+        }
+    }
+}
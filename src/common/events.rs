@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+use tokio::sync::broadcast;
+
+use crate::common::{storage::PeerInfo, wg::PublicKey};
+
+pub type Peers = HashMap<String, HashMap<PublicKey, PeerInfo>>;
+
+#[derive(Clone)]
+pub enum PeerEvent {
+    Added {
+        account: String,
+        public_key: PublicKey,
+        info: PeerInfo,
+    },
+    Removed {
+        account: String,
+        public_key: PublicKey,
+        info: PeerInfo,
+    },
+    // поменялся срок аренды или адрес подтвержден
+    Renewed {
+        account: String,
+        public_key: PublicKey,
+        info: PeerInfo,
+    },
+    // пир сменил ключ через ReserveIp с previous_public_key, адреса остались прежними
+    KeyRotated {
+        account: String,
+        old_public_key: PublicKey,
+        public_key: PublicKey,
+        info: PeerInfo,
+    },
+}
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref SENDER: broadcast::Sender<PeerEvent> = broadcast::channel(CHANNEL_CAPACITY).0;
+}
+
+// события отправляются после успешного изменения, пока хранилище еще заблокировано,
+// поэтому снимок, взятый под той же блокировкой, с ними согласован
+pub fn publish(event: PeerEvent) {
+    // ошибка означает только то, что подписчиков нет
+    let _ = SENDER.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<PeerEvent> {
+    SENDER.subscribe()
+}
+
+fn flatten(peers: &Peers) -> HashMap<PublicKey, (&str, &PeerInfo)> {
+    peers
+        .iter()
+        .flat_map(|(account, peers)| {
+            peers
+                .iter()
+                .map(move |(public_key, info)| (*public_key, (account.as_str(), info)))
+        })
+        .collect()
+}
+
+// правки из cli (add, import, revoke) делает другой процесс, runserver видит их
+// только при перечитывании хранилища и восстанавливает события сравнением
+pub fn external_changes(old: &Peers, new: &Peers) {
+    let old = flatten(old);
+    let new = flatten(new);
+    for (public_key, (account, info)) in &old {
+        match new.get(public_key) {
+            Some((new_account, new_info)) if new_account == account => {
+                if new_info != info {
+                    publish(PeerEvent::Renewed {
+                        account: account.to_string(),
+                        public_key: *public_key,
+                        info: (*new_info).clone(),
+                    });
+                }
+            }
+            // перенос в другой account виден как удаление и добавление
+            _ => publish(PeerEvent::Removed {
+                account: account.to_string(),
+                public_key: *public_key,
+                info: (*info).clone(),
+            }),
+        }
+    }
+    for (public_key, (account, info)) in &new {
+        if old
+            .get(public_key)
+            .is_none_or(|(old_account, _)| old_account != account)
+        {
+            publish(PeerEvent::Added {
+                account: account.to_string(),
+                public_key: *public_key,
+                info: (*info).clone(),
+            });
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::net::IpAddr;
+use std::process::ExitStatus;
+
+use ipnet::IpNet;
+use tokio::process::Command;
+
+#[derive(thiserror::Error, Debug)]
+pub enum IpError {
+    #[error("failed to run ip: {}", .0)]
+    IO(#[from] std::io::Error),
+    #[error("ip finished with {}", .0)]
+    Status(ExitStatus),
+    #[error("cannot parse ip output: {}", .0)]
+    Parse(String),
+}
+
+impl From<IpError> for tonic::Status {
+    fn from(value: IpError) -> Self {
+        tonic::Status::internal(value.to_string())
+    }
+}
+
+// маршруты к подсетям за пирами помечаются своим номером proto, чтобы отличать их
+// от маршрутов адресов интерфейса, пулов делегирования и добавленных администратором,
+// номер не занят ни одним протоколом из /etc/iproute2/rt_protos
+const PROTO: &str = "125";
+
+fn family(net: &IpNet) -> &'static str {
+    match net {
+        IpNet::V4(_) => "-4",
+        IpNet::V6(_) => "-6",
+    }
+}
+
+async fn run(args: &[&str]) -> Result<String, IpError> {
+    let output = Command::new("ip").args(args).output().await?;
+    if !output.status.success() {
+        return Err(IpError::Status(output.status));
+    }
+    String::from_utf8(output.stdout).map_err(|e| IpError::Parse(e.to_string()))
+}
+
+pub async fn add_route(interface: &str, net: &IpNet) -> Result<(), IpError> {
+    let net_string = net.to_string();
+    run(&[
+        family(net),
+        "route",
+        "replace",
+        &net_string,
+        "dev",
+        interface,
+        "proto",
+        PROTO,
+    ])
+    .await?;
+    Ok(())
+}
+
+pub async fn remove_route(interface: &str, net: &IpNet) -> Result<(), IpError> {
+    let net_string = net.to_string();
+    run(&[
+        family(net),
+        "route",
+        "del",
+        &net_string,
+        "dev",
+        interface,
+        "proto",
+        PROTO,
+    ])
+    .await?;
+    Ok(())
+}
+
+// первое поле строки `ip route show` - сеть, у маршрута к хосту без длины префикса
+fn parse_route(line: &str) -> Result<IpNet, IpError> {
+    let destination = line.split_whitespace().next().unwrap_or_default();
+    destination
+        .parse()
+        .or_else(|_| destination.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| IpError::Parse(format!("unexpected route line '{}'", line)))
+}
+
+pub async fn routes(interface: &str) -> Result<Vec<IpNet>, IpError> {
+    let mut routes = vec![];
+    for family in ["-4", "-6"] {
+        let output = run(&[family, "route", "show", "dev", interface, "proto", PROTO]).await?;
+        for line in output.lines().filter(|x| !x.is_empty()) {
+            routes.push(parse_route(line)?);
+        }
+    }
+    Ok(routes)
+}
@@ -1,5 +1,11 @@
+pub mod allocator;
 pub mod config;
 pub mod custom;
+pub mod events;
+pub mod ipcli;
+pub mod proof;
 pub mod proto;
 pub mod storage;
 pub mod wg;
+pub mod wgcli;
+pub mod wgquick;
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+
+use crate::common::wg::{EphemeralSecret, PrivateKey, PublicKey, SharedSecret};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(60);
+// ограничения на число неиспользованных nonce, иначе GetChallenge раздувает память сервера
+const MAX_CHALLENGES: usize = 4096;
+const MAX_CHALLENGES_PER_ACCOUNT: usize = 16;
+
+struct Challenge {
+    secret: EphemeralSecret,
+    issued: Instant,
+    // account, под которым клиент вошел, None без аутентификации
+    account: Option<String>,
+}
+
+lazy_static! {
+    static ref CHALLENGES: Mutex<HashMap<Vec<u8>, Challenge>> = Mutex::new(HashMap::new());
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProofError {
+    #[error("unknown or expired challenge")]
+    UnknownChallenge,
+    #[error("invalid proof of possession of the private key")]
+    InvalidProof,
+    #[error("too many outstanding challenges, try again later")]
+    TooManyChallenges,
+}
+
+fn mac(shared: &SharedSecret, nonce: &[u8], public_key: &PublicKey, account: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(shared.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(nonce);
+    mac.update(public_key.as_bytes());
+    mac.update(account.as_bytes());
+    mac
+}
+
+// клиентская сторона: доказательство того, что у клиента есть приватный ключ
+pub fn prove(
+    private_key: &PrivateKey,
+    server_key: &PublicKey,
+    nonce: &[u8],
+    account: &str,
+) -> Vec<u8> {
+    let shared = private_key.diffie_hellman(server_key);
+    let public_key = PublicKey::from(private_key);
+    mac(&shared, nonce, &public_key, account)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+// серверная сторона: выдает nonce и одноразовый x25519 ключ
+pub fn issue(account: Option<&str>) -> Result<(Vec<u8>, PublicKey), ProofError> {
+    let mut challenges = CHALLENGES.lock().unwrap();
+    challenges.retain(|_, challenge| challenge.issued.elapsed() < CHALLENGE_TIMEOUT);
+    let of_account = || {
+        challenges
+            .values()
+            .filter(|challenge| challenge.account.as_deref() == account)
+            .count()
+    };
+    if challenges.len() >= MAX_CHALLENGES
+        || (account.is_some() && of_account() >= MAX_CHALLENGES_PER_ACCOUNT)
+    {
+        return Err(ProofError::TooManyChallenges);
+    }
+
+    let mut nonce = vec![0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let server_key = PublicKey::from(&secret);
+    challenges.insert(
+        nonce.clone(),
+        Challenge {
+            secret,
+            issued: Instant::now(),
+            account: account.map(str::to_string),
+        },
+    );
+    Ok((nonce, server_key))
+}
+
+// каждый nonce можно использовать только один раз, даже если проверка не прошла
+pub fn verify(
+    nonce: &[u8],
+    public_key: &PublicKey,
+    account: &str,
+    proof: &[u8],
+) -> Result<(), ProofError> {
+    let challenge = CHALLENGES
+        .lock()
+        .unwrap()
+        .remove(nonce)
+        .filter(|challenge| challenge.issued.elapsed() < CHALLENGE_TIMEOUT)
+        .ok_or(ProofError::UnknownChallenge)?;
+    let shared = challenge.secret.diffie_hellman(public_key);
+    if !shared.was_contributory() {
+        return Err(ProofError::InvalidProof);
+    }
+    mac(&shared, nonce, public_key, account)
+        .verify_slice(proof)
+        .map_err(|_| ProofError::InvalidProof)
+}
@@ -1,25 +1,33 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ops::{Deref, DerefMut},
 };
 
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
+use serde_with::{
+    formats::{CommaSeparator, PreferOne},
+    serde_as, OneOrMany, StringWithSeparator,
+};
 use tokio::{
     fs::{self, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt as _},
 };
 
 use super::custom::Endpoint;
+use super::proto::PoolUsage;
 
 use crate::common::{
-    config::CONFIG,
+    allocator::{self, AddressIndex},
+    config::{AccountPools, Allocation, HashBy, WhenFull, CONFIG},
+    events,
     wg::{self, SerdeBase64},
 };
 use derive_more::From;
 use ipnet::IpNet;
 use lazy_static::lazy_static;
+use sha2::{Digest as _, Sha256};
 use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[serde_as]
 #[derive(Serialize, Deserialize)]
@@ -29,15 +37,92 @@ pub struct ServerInfo {
     pub endpoint: Endpoint,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before unix epoch")
+        .as_secs()
+}
+
+pub fn lease_expiry() -> Option<u64> {
+    CONFIG.lease_duration.map(|duration| now() + duration)
+}
+
+// новый ключ для каждого резервирования, если они включены в конфигурации
+pub fn new_preshared_key() -> Option<wg::PresharedKey> {
+    CONFIG.preshared_keys.then(wg::PresharedKey::gen)
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct PeerInfo {
-    pub internal_addr: IpAddr,
+    // по одному адресу из каждого пула интерфейса
+    #[serde(rename = "internal_addr")]
+    #[serde_as(as = "OneOrMany<_, PreferOne>")]
+    pub addresses: Vec<IpAddr>,
+    // unix время окончания аренды, None - аренда бессрочная
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    // адрес только предложен и еще не подтвержден клиентом
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub offered: bool,
+    #[serde_as(as = "Option<SerdeBase64>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<wg::PresharedKey>,
+    // префиксы из delegation_pools, маршрутизируемые через пира
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delegated: Vec<IpNet>,
+    // подсети за пиром, которые он объявил сам
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<IpNet>,
 }
 
-impl From<IpAddr> for PeerInfo {
-    fn from(value: IpAddr) -> Self {
+impl PeerInfo {
+    pub fn allowed_ips(&self) -> Vec<IpNet> {
+        self.addresses
+            .iter()
+            .map(|addr| (*addr).into())
+            .chain(self.delegated.iter().copied())
+            .chain(self.routes.iter().copied())
+            .collect()
+    }
+    pub fn display_addresses(&self) -> String {
+        self.addresses
+            .iter()
+            .map(IpAddr::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+    pub fn offer(addresses: Vec<IpAddr>) -> Self {
         PeerInfo {
-            internal_addr: value,
+            addresses,
+            expires_at: Some(now() + CONFIG.offer_timeout),
+            offered: true,
+            preshared_key: new_preshared_key(),
+            delegated: vec![],
+            routes: vec![],
+        }
+    }
+    pub fn confirm(&mut self) {
+        if self.offered {
+            self.offered = false;
+            self.expires_at = lease_expiry();
+        }
+    }
+}
+
+impl From<Vec<IpAddr>> for PeerInfo {
+    fn from(value: Vec<IpAddr>) -> Self {
+        PeerInfo {
+            addresses: value,
+            expires_at: None,
+            offered: false,
+            preshared_key: None,
+            delegated: vec![],
+            routes: vec![],
         }
     }
 }
@@ -49,7 +134,13 @@ pub struct Interface {
     pub listen_port: u16,
     #[serde_as(as = "SerdeBase64")]
     pub private_key: wg::PrivateKey,
-    pub address: IpNet,
+    // адреса сервера, их сети - пулы, из которых выдаются адреса пирам
+    #[serde(rename = "Address")]
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, IpNet>")]
+    pub addresses: Vec<IpNet>,
+    // переносится в SaveConfig при экспорте в wg-quick
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub save_config: bool,
 }
 
 #[derive(Serialize, From)]
@@ -64,9 +155,16 @@ pub struct WgConfigInterface<'a> {
 pub struct PeerConfig<'a> {
     #[serde_as(as = "SerdeBase64")]
     pub public_key: &'a wg::PublicKey,
+    #[serde_as(as = "Option<SerdeBase64>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<wg::PresharedKey>,
     #[serde(rename(serialize = "AllowedIPs"))]
-    pub allowed_ips: &'a IpNet,
-    pub endpoint: &'a Endpoint,
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, IpNet>")]
+    pub allowed_ips: Vec<IpNet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<&'a Endpoint>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persistent_keepalive: Option<u16>,
 }
 
 #[derive(Serialize, From)]
@@ -75,6 +173,13 @@ pub struct WgConfigPeer<'a> {
     pub peer: &'a PeerConfig<'a>,
 }
 
+// секции конфигурации wg-quick сериализуются как toml без кавычек
+pub fn to_wg_quick<T: Serialize>(section: &T) -> Result<String, toml::ser::Error> {
+    let mut rendered = toml::to_string(section)?;
+    rendered.remove_matches('"');
+    Ok(rendered)
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize)]
 pub struct Storage {
@@ -82,43 +187,737 @@ pub struct Storage {
     pub server: ServerInfo,
     #[serde_as(as = "HashMap<_, HashMap<SerdeBase64, _>>")]
     pub peers: HashMap<String, HashMap<wg::PublicKey, PeerInfo>>,
+    // в режиме drain новые пиры не принимаются
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub draining: bool,
+    // строится при первом выделении адреса, дальше обновляется в push и remove
+    #[serde(skip)]
+    pub index: Option<Vec<AddressIndex>>,
+    // когда адреса были освобождены, ведется только для least_recently_released
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub released: HashMap<IpAddr, u64>,
+    // подсети пулов, закрепленные за account, заполняется с account_pools в конфигурации
+    // и не освобождается вместе с пирами, чтобы правила firewall оставались верными
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub account_pools: HashMap<String, Vec<IpNet>>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AllocateError {
+    #[error("all ip addresses of {} are in use", .0)]
+    Exhausted(IpNet),
+    #[error("address {} is not a host address of any pool", .0)]
+    OutOfPool(IpAddr),
+    #[error("address {} is already in use", .0)]
+    InUse(IpAddr),
+    #[error("address {} is reserved for static assignments", .0)]
+    Reserved(IpAddr),
+    #[error("address {} differs from the static assignment of the peer", .0)]
+    StaticMismatch(IpAddr),
+    #[error("address {} is excluded from allocation", .0)]
+    Excluded(IpAddr),
+    #[error("address {} is inside the pool of account '{}'", .0, .1)]
+    ForeignAccountPool(IpAddr, String),
+    #[error("address {} is outside the pool of the account", .0)]
+    OutsideAccountPool(IpAddr),
+    #[error("no delegation pool fits prefix length {}", .0)]
+    NoDelegationPool(u8),
+    #[error("route {} is not allowed for the account", .0)]
+    RouteNotAllowed(IpNet),
+    #[error("route {} overlaps a prefix of '{}'", .0, .1)]
+    RouteInUse(IpNet, String),
+    #[error("route {} overlaps pool {}", .0, .1)]
+    RouteOverlapsPool(IpNet, IpNet),
+    #[error("prefix {} has host bits set, use {}", .0, .0.trunc())]
+    HostBits(IpNet),
+    #[error("public key is already registered for '{}'", .0)]
+    KeyInUse(String),
+    #[error("public key is already reserved with other addresses, prefix or routes")]
+    ReservedDifferently,
+}
+
+impl From<AllocateError> for tonic::Status {
+    fn from(value: AllocateError) -> Self {
+        match value {
+            AllocateError::Exhausted(..) => tonic::Status::resource_exhausted(value.to_string()),
+            AllocateError::OutOfPool(..)
+            | AllocateError::Excluded(..)
+            | AllocateError::StaticMismatch(..)
+            | AllocateError::OutsideAccountPool(..)
+            | AllocateError::NoDelegationPool(..)
+            | AllocateError::RouteOverlapsPool(..)
+            | AllocateError::HostBits(..) => tonic::Status::invalid_argument(value.to_string()),
+            AllocateError::RouteNotAllowed(..) => {
+                tonic::Status::permission_denied(value.to_string())
+            }
+            AllocateError::InUse(..)
+            | AllocateError::RouteInUse(..)
+            | AllocateError::Reserved(..)
+            | AllocateError::ForeignAccountPool(..)
+            | AllocateError::KeyInUse(..)
+            | AllocateError::ReservedDifferently => {
+                tonic::Status::already_exists(value.to_string())
+            }
+        }
+    }
+}
+
+// повторяет логику IpNet::hosts без перебора всей сети
+pub fn is_host(net: &IpNet, addr: IpAddr) -> bool {
+    match (net, addr) {
+        (IpNet::V4(net), IpAddr::V4(addr)) if net.prefix_len() < 31 => {
+            net.contains(&addr) && addr != net.network() && addr != net.broadcast()
+        }
+        // в IPv6 нулевой адрес сети занят под subnet-router anycast
+        (IpNet::V6(net), IpAddr::V6(addr)) if net.prefix_len() < 127 => {
+            net.contains(&addr) && addr != net.network()
+        }
+        _ => net.contains(&addr),
+    }
+}
+
+// ядро хранит маршруты и AllowedIPs без битов хоста, и с ними reconciler
+// каждый раз видел бы расхождение
+pub fn check_host_bits(net: &IpNet) -> Result<(), AllocateError> {
+    match *net == net.trunc() {
+        true => Ok(()),
+        false => Err(AllocateError::HostBits(*net)),
+    }
+}
+
+// смещение адреса в пуле для стратегии hash
+fn hash_offset(by: HashBy, account: &str, public_key: &wg::PublicKey) -> u128 {
+    let digest = match by {
+        HashBy::PublicKey => Sha256::digest(public_key.as_bytes()),
+        HashBy::Account => Sha256::digest(account.as_bytes()),
+    };
+    u128::from_be_bytes(digest[..16].try_into().unwrap())
+}
+
+// освобожденные адреса в least_recently_released в индекс не возвращаются,
+// поэтому в нем остаются только адреса, которые еще никому не выдавались
+fn build_index(
+    interface: &Interface,
+    peers: &HashMap<String, HashMap<wg::PublicKey, PeerInfo>>,
+    released: &HashMap<IpAddr, u64>,
+) -> Vec<AddressIndex> {
+    interface
+        .addresses
+        .iter()
+        .map(|address| {
+            let mut index = AddressIndex::new(address.trunc());
+            for address in &interface.addresses {
+                index.take(address.addr());
+            }
+            for assignment in &CONFIG.static_assignments {
+                assignment.address.iter().for_each(|addr| index.take(*addr));
+            }
+            for net in CONFIG.exclude.iter().chain(&CONFIG.reserved) {
+                index.take_net(net);
+            }
+            for info in peers.values().flat_map(HashMap::values) {
+                info.addresses.iter().for_each(|addr| index.take(*addr));
+            }
+            if let Allocation::LeastRecentlyReleased { .. } = CONFIG.allocation {
+                released.keys().for_each(|addr| index.take(*addr));
+            }
+            index
+        })
+        .collect()
 }
 
 impl Storage {
-    pub fn find_ip(&self) -> Option<IpAddr> {
-        let mut used_ips: Vec<_> = self
+    fn index(&mut self) -> &mut Vec<AddressIndex> {
+        if self.index.is_none() {
+            // конфигурация могла измениться с момента освобождения адресов
+            let stale: Vec<_> = self
+                .released
+                .keys()
+                .filter(|addr| !self.is_dynamic(**addr))
+                .copied()
+                .collect();
+            for addr in stale {
+                self.released.remove(&addr);
+            }
+        }
+        self.index
+            .get_or_insert_with(|| build_index(&self.interface, &self.peers, &self.released))
+    }
+    // net - пул или подсеть account внутри него
+    pub fn find_ip(
+        &mut self,
+        net: &IpNet,
+        account: &str,
+        public_key: &wg::PublicKey,
+    ) -> Option<IpAddr> {
+        let index = self
+            .index()
+            .iter()
+            .find(|index| index.pool().contains(net))?;
+        match CONFIG.allocation {
+            Allocation::Sequential => index.free_in(net, 0),
+            Allocation::Random => index.free_in(net, rand::random()),
+            Allocation::Hash { by } => index.free_in(net, hash_offset(by, account, public_key)),
+            Allocation::LeastRecentlyReleased { quarantine } => {
+                if let Some(addr) = index.free_in(net, 0) {
+                    return Some(addr);
+                }
+                let now = now();
+                self.released
+                    .iter()
+                    .filter(|(addr, released)| {
+                        net.contains(*addr) && **released + quarantine <= now
+                    })
+                    .min_by_key(|(_, released)| **released)
+                    .map(|(addr, _)| *addr)
+            }
+        }
+    }
+    // подсети, которые account взять не может: закрепленные за другими account
+    // и содержащие адреса их пиров или закрепленные за ними адреса
+    fn foreign_subnets(&self, account: &str, prefix_len: u8) -> HashMap<IpNet, &str> {
+        let subnet_of = |addr: &IpAddr| IpNet::new(*addr, prefix_len).ok().map(|net| net.trunc());
+        let mut used = HashMap::new();
+        for (owner, subnets) in &self.account_pools {
+            if owner != account {
+                used.extend(subnets.iter().map(|subnet| (*subnet, owner.as_str())));
+            }
+        }
+        for (owner, peers) in &self.peers {
+            if owner != account {
+                used.extend(
+                    peers
+                        .values()
+                        .flat_map(|info| &info.addresses)
+                        .filter_map(subnet_of)
+                        .map(|subnet| (subnet, owner.as_str())),
+                );
+            }
+        }
+        for assignment in &CONFIG.static_assignments {
+            if assignment.account != account {
+                used.extend(
+                    assignment
+                        .address
+                        .iter()
+                        .filter_map(subnet_of)
+                        .map(|subnet| (subnet, assignment.account.as_str())),
+                );
+            }
+        }
+        used
+    }
+    // адрес из подсетей account, новая подсеть берется при первом резервировании
+    // и, если это разрешено в конфигурации, когда старые заполнены
+    fn allocate_in_account_pool(
+        &mut self,
+        pool: &IpNet,
+        account: &str,
+        public_key: &wg::PublicKey,
+        settings: &AccountPools,
+    ) -> Result<IpAddr, AllocateError> {
+        let subnets: Vec<_> = self
+            .account_pools
+            .get(account)
+            .into_iter()
+            .flatten()
+            .filter(|subnet| pool.contains(*subnet))
+            .copied()
+            .collect();
+        for subnet in &subnets {
+            if let Some(addr) = self.find_ip(subnet, account, public_key) {
+                return Ok(addr);
+            }
+        }
+        if !subnets.is_empty() && settings.when_full == WhenFull::Reject {
+            return Err(AllocateError::Exhausted(subnets[0]));
+        }
+        let prefix_len = settings.prefix_len(pool);
+        // подсеть, целиком попавшая в exclude или reserved, account не достается,
+        // а занятые сети first_free_prefix обходит за один проход, сколько бы их ни было
+        let mut occupied: Vec<IpNet> = self
+            .foreign_subnets(account, prefix_len)
+            .into_keys()
+            .chain(subnets)
+            .chain(
+                CONFIG
+                    .exclude
+                    .iter()
+                    .chain(&CONFIG.reserved)
+                    .filter(|net| net.prefix_len() <= prefix_len)
+                    .copied(),
+            )
+            .collect();
+        while let Some(subnet) = allocator::first_free_prefix(pool, prefix_len, &occupied) {
+            if let Some(addr) = self.find_ip(&subnet, account, public_key) {
+                self.account_pools
+                    .entry(account.to_string())
+                    .or_default()
+                    .push(subnet);
+                return Ok(addr);
+            }
+            occupied.push(subnet);
+        }
+        Err(AllocateError::Exhausted(*pool))
+    }
+    // адрес вне подсетей account можно запросить, только если его подсеть свободна
+    // и account разрешено брать еще одну подсеть пула
+    fn check_account_subnet(&self, account: &str, addr: &IpAddr) -> Result<(), AllocateError> {
+        let Some(settings) = &CONFIG.account_pools else {
+            return Ok(());
+        };
+        let Some(pool) = self.pools().find(|pool| pool.contains(addr)) else {
+            return Ok(());
+        };
+        let has_subnet = self
+            .account_pools
+            .get(account)
+            .is_some_and(|subnets| subnets.iter().any(|subnet| pool.contains(subnet)));
+        if has_subnet && settings.when_full == WhenFull::Reject {
+            return Err(AllocateError::OutsideAccountPool(*addr));
+        }
+        let prefix_len = settings.prefix_len(&pool);
+        let subnet = IpNet::new(*addr, prefix_len)
+            .expect("prefix length is checked by config validation")
+            .trunc();
+        match self.foreign_subnets(account, prefix_len).get(&subnet) {
+            Some(owner) => Err(AllocateError::ForeignAccountPool(*addr, owner.to_string())),
+            None => Ok(()),
+        }
+    }
+    // владелец подсети, в которую попадает адрес
+    fn account_pool_owner(&self, addr: &IpAddr) -> Option<&str> {
+        self.account_pools
+            .iter()
+            .find(|(_, subnets)| subnets.iter().any(|subnet| subnet.contains(addr)))
+            .map(|(account, _)| account.as_str())
+    }
+    // какому account делегирован или объявлен им префикс, пересекающийся с net
+    pub fn prefix_owner(&self, net: &IpNet) -> Option<&str> {
+        self.peers.iter().find_map(|(account, peers)| {
+            peers
+                .values()
+                .flat_map(|info| info.delegated.iter().chain(&info.routes))
+                .any(|prefix| prefix.contains(net) || net.contains(prefix))
+                .then_some(account.as_str())
+        })
+    }
+    // подсети за остальными пирами, их клиенты маршрутизируют через сервер
+    pub fn advertised_routes(&self, except: &wg::PublicKey) -> Vec<IpNet> {
+        let mut routes: Vec<_> = self
             .peers
             .values()
             .flatten()
-            .map(|x| x.1.internal_addr)
+            .filter(|(public_key, _)| *public_key != except)
+            .flat_map(|(_, info)| info.routes.iter().copied())
             .collect();
-        used_ips.push(self.interface.address.addr());
-        let mut found = Option::<IpAddr>::None;
-        for addr in self.interface.address.hosts() {
-            if !used_ips.contains(&addr) {
-                found = Some(addr);
-                break;
-            }
+        routes.sort();
+        routes
+    }
+    // первый свободный префикс из первого пула делегирования, в который он помещается
+    fn delegate(&self, prefix_len: u8) -> Result<IpNet, AllocateError> {
+        let pools: Vec<_> = CONFIG
+            .delegation_pools
+            .iter()
+            .filter(|pool| pool.prefix_len() <= prefix_len && prefix_len <= pool.max_prefix_len())
+            .collect();
+        let Some(first) = pools.first() else {
+            return Err(AllocateError::NoDelegationPool(prefix_len));
+        };
+        let occupied: Vec<_> = self
+            .peers
+            .values()
+            .flat_map(HashMap::values)
+            .flat_map(|info| info.delegated.iter().chain(&info.routes))
+            .copied()
+            .collect();
+        pools
+            .iter()
+            .find_map(|pool| allocator::first_free_prefix(pool, prefix_len, &occupied))
+            .ok_or(AllocateError::Exhausted(**first))
+    }
+    // можно ли по конфигурации выдать адрес динамически
+    fn is_dynamic(&self, addr: IpAddr) -> bool {
+        !CONFIG.is_static(&addr)
+            && !CONFIG.is_excluded(&addr)
+            && !CONFIG.is_reserved(&addr)
+            && self.pools().any(|pool| is_host(&pool, addr))
+    }
+    // адрес освобождается, только если его можно выдать динамически
+    fn release(&mut self, addr: IpAddr) {
+        if self.owner_of(addr).is_some() || !self.is_dynamic(addr) {
+            return;
+        }
+        if let Allocation::LeastRecentlyReleased { .. } = CONFIG.allocation {
+            self.released.insert(addr, now());
+            return;
+        }
+        if let Some(index) = &mut self.index {
+            index.iter_mut().for_each(|index| index.release(addr));
         }
-        found
     }
     pub fn push(&mut self, account: &str, public_key: wg::PublicKey, peer: PeerInfo) -> PeerInfo {
         let _ = self.peers.try_insert(account.into(), Default::default());
         let peers_of_account = self.peers.get_mut(account).unwrap();
 
         match peers_of_account.try_insert(public_key, peer) {
-            Ok(a) => a.clone(),
+            Ok(a) => {
+                for addr in &a.addresses {
+                    self.released.remove(addr);
+                }
+                if let Some(index) = &mut self.index {
+                    for addr in &a.addresses {
+                        index.iter_mut().for_each(|index| index.take(*addr));
+                    }
+                }
+                a.clone()
+            }
             Err(occupied) => occupied.entry.get().clone(),
         }
     }
+    // общий путь выдачи адреса для ReserveIp и команды add: повторный запрос
+    // с тем же ключом возвращает уже выданный адрес.
+    // запрошенный адрес занимает место в своем пуле, из остальных берется свободный,
+    // запрошенный адрес должен совпадать с закрепленным в static_assignments
+    pub fn reserve(
+        &mut self,
+        account: &str,
+        public_key: wg::PublicKey,
+        requested: &[IpAddr],
+        delegated_prefix_len: Option<u8>,
+        routes: &[IpNet],
+        new_peer: impl FnOnce(Vec<IpAddr>) -> PeerInfo,
+    ) -> Result<PeerInfo, AllocateError> {
+        routes.iter().try_for_each(check_host_bits)?;
+        match self.account_of(&public_key) {
+            // повтор должен совпадать с исходным запросом, иначе новые
+            // префикс или маршруты молча потерялись бы
+            Some(owner) if owner == account => {
+                let peer = &self.peers[account][&public_key];
+                let same_prefix = match delegated_prefix_len {
+                    Some(prefix_len) => peer
+                        .delegated
+                        .iter()
+                        .any(|prefix| prefix.prefix_len() == prefix_len),
+                    None => peer.delegated.is_empty(),
+                };
+                let same_routes = routes.len() == peer.routes.len()
+                    && routes.iter().all(|route| peer.routes.contains(route));
+                if !same_prefix
+                    || !same_routes
+                    || !requested.iter().all(|addr| peer.addresses.contains(addr))
+                {
+                    return Err(AllocateError::ReservedDifferently);
+                }
+                return Ok(peer.clone());
+            }
+            Some(owner) => return Err(AllocateError::KeyInUse(owner.to_string())),
+            None => {}
+        }
+        // адрес, закрепленный за всем account, достается только одному его пиру,
+        // остальные получают адреса как обычно
+        let assignment = CONFIG
+            .static_assignment(account, &public_key)
+            .filter(|assignment| {
+                assignment.public_key.is_some()
+                    || assignment
+                        .address
+                        .iter()
+                        .all(|addr| self.owner_of(*addr).is_none())
+            });
+        if let Some(assignment) = assignment {
+            if let Some(addr) = requested
+                .iter()
+                .find(|addr| !assignment.address.contains(addr))
+            {
+                return Err(AllocateError::StaticMismatch(*addr));
+            }
+        }
+        let requested = assignment.map_or(requested, |assignment| &assignment.address);
+        for addr in requested {
+            if !self.pools().any(|pool| is_host(&pool, *addr)) {
+                return Err(AllocateError::OutOfPool(*addr));
+            }
+            if CONFIG.is_excluded(addr) {
+                return Err(AllocateError::Excluded(*addr));
+            }
+            if self.owner_of(*addr).is_some() {
+                return Err(AllocateError::InUse(*addr));
+            }
+            if assignment.is_none() && (CONFIG.is_static(addr) || CONFIG.is_reserved(addr)) {
+                return Err(AllocateError::Reserved(*addr));
+            }
+            match self.account_pool_owner(addr) {
+                Some(owner) if assignment.is_none() && owner != account => {
+                    return Err(AllocateError::ForeignAccountPool(*addr, owner.to_string()));
+                }
+                None if assignment.is_none() => self.check_account_subnet(account, addr)?,
+                _ => {}
+            }
+        }
+        for route in routes {
+            if !CONFIG.route_allowed(account, route) {
+                return Err(AllocateError::RouteNotAllowed(*route));
+            }
+            // маршрут поверх пула перебил бы маршрут интерфейса к нему,
+            // адреса сервера лежат в пулах и проверяются вместе с ними
+            if let Some(pool) = self
+                .pools()
+                .chain(CONFIG.delegation_pools.iter().copied())
+                .find(|pool| pool.contains(route) || route.contains(pool))
+            {
+                return Err(AllocateError::RouteOverlapsPool(*route, pool));
+            }
+            if let Some(owner) = self.prefix_owner(route) {
+                return Err(AllocateError::RouteInUse(*route, owner.to_string()));
+            }
+        }
+        let mut addresses = vec![];
+        for pool in self.pools().collect::<Vec<_>>() {
+            let addr = match requested.iter().find(|addr| is_host(&pool, **addr)) {
+                Some(addr) => {
+                    // запрошенный адрес вне подсетей account забирает его подсеть
+                    if let (Some(settings), None) = (&CONFIG.account_pools, assignment) {
+                        if self.account_pool_owner(addr).is_none() {
+                            let subnet = IpNet::new(*addr, settings.prefix_len(&pool))
+                                .expect("prefix length is checked by config validation")
+                                .trunc();
+                            self.account_pools
+                                .entry(account.to_string())
+                                .or_default()
+                                .push(subnet);
+                        }
+                    }
+                    *addr
+                }
+                None => match &CONFIG.account_pools {
+                    Some(settings) => {
+                        self.allocate_in_account_pool(&pool, account, &public_key, settings)?
+                    }
+                    None => self
+                        .find_ip(&pool, account, &public_key)
+                        .ok_or(AllocateError::Exhausted(pool))?,
+                },
+            };
+            addresses.push(addr);
+        }
+        let delegated = delegated_prefix_len
+            .map(|prefix_len| self.delegate(prefix_len))
+            .transpose()?;
+        let peer = PeerInfo {
+            delegated: delegated.into_iter().collect(),
+            routes: routes.to_vec(),
+            ..new_peer(addresses)
+        };
+        Ok(self.push(account, public_key, peer))
+    }
+    pub fn pools(&self) -> impl Iterator<Item = IpNet> + '_ {
+        self.interface.addresses.iter().map(IpNet::trunc)
+    }
+    // адреса пира с длиной префикса их пулов, как их назначает себе клиент
+    pub fn interface_addresses(&self, info: &PeerInfo) -> Vec<IpNet> {
+        info.addresses
+            .iter()
+            .map(|addr| {
+                let prefix_len = self
+                    .pools()
+                    .find(|pool| pool.contains(addr))
+                    .map_or(IpNet::from(*addr).prefix_len(), |pool| pool.prefix_len());
+                IpNet::new(*addr, prefix_len).expect("prefix length is taken from a network")
+            })
+            .collect()
+    }
+    // кому принадлежит адрес: серверу (None в account) или пиру
+    pub fn owner_of(&self, addr: IpAddr) -> Option<Option<&str>> {
+        if self
+            .interface
+            .addresses
+            .iter()
+            .any(|net| net.addr() == addr)
+        {
+            return Some(None);
+        }
+        self.peers.iter().find_map(|(account, peers)| {
+            peers
+                .values()
+                .any(|info| info.addresses.contains(&addr))
+                .then_some(Some(account.as_str()))
+        })
+    }
+    pub fn account_of(&self, public_key: &wg::PublicKey) -> Option<&str> {
+        self.peers
+            .iter()
+            .find(|(_, peers)| peers.contains_key(public_key))
+            .map(|(account, _)| account.as_str())
+    }
+    // новый ключ получает адреса, префиксы и аренду старого
+    pub fn rotate_key(
+        &mut self,
+        account: &str,
+        old: &wg::PublicKey,
+        new: wg::PublicKey,
+    ) -> Option<PeerInfo> {
+        let peers = self.peers.get_mut(account)?;
+        let info = peers.remove(old)?;
+        peers.insert(new, info.clone());
+        Some(info)
+    }
+    // маршруты пира должны быть разрешены новому account, а с account_pools
+    // адреса вне его подсетей выдаются заново, клиенту придется переподключиться
+    pub fn move_peer(
+        &mut self,
+        public_key: &wg::PublicKey,
+        account: &str,
+    ) -> Option<Result<PeerInfo, AllocateError>> {
+        let from = self.account_of(public_key)?.to_string();
+        let routes = &self.peers[&from][public_key].routes;
+        if let Some(route) = routes
+            .iter()
+            .find(|route| !CONFIG.route_allowed(account, route))
+        {
+            return Some(Err(AllocateError::RouteNotAllowed(*route)));
+        }
+        let peer = self.remove(&from, public_key)?;
+        let mut moved = peer.clone();
+        if let Some(settings) = &CONFIG.account_pools {
+            // подсети, взятые для неудавшегося переноса, возвращаются вместе с пиром
+            let claimed = self.account_pools.get(account).cloned();
+            let mut addresses = vec![];
+            for addr in &peer.addresses {
+                let pool = self.pools().find(|pool| pool.contains(addr));
+                let addr = match pool {
+                    Some(pool) if self.account_pool_owner(addr) != Some(account) => {
+                        match self.allocate_in_account_pool(&pool, account, public_key, settings) {
+                            Ok(addr) => addr,
+                            Err(error) => {
+                                match claimed {
+                                    Some(subnets) => {
+                                        self.account_pools.insert(account.into(), subnets)
+                                    }
+                                    None => self.account_pools.remove(account),
+                                };
+                                self.push(&from, *public_key, peer);
+                                return Some(Err(error));
+                            }
+                        }
+                    }
+                    _ => *addr,
+                };
+                addresses.push(addr);
+            }
+            moved.addresses = addresses;
+        }
+        Some(Ok(self.push(account, *public_key, moved)))
+    }
+    pub fn pool_usage(&self) -> Vec<PoolUsage> {
+        self.pools()
+            .map(|pool| {
+                // адреса сервера, exclude и reserved пирам не выдаются вовсе,
+                // а закрепленные - только своим пирам, поэтому свободными не считаются
+                let mut index = AddressIndex::new(pool);
+                for address in &self.interface.addresses {
+                    index.take(address.addr());
+                }
+                for net in CONFIG.exclude.iter().chain(&CONFIG.reserved) {
+                    index.take_net(net);
+                }
+                let mut assigned = HashSet::new();
+                for addr in CONFIG
+                    .static_assignments
+                    .iter()
+                    .flat_map(|assignment| &assignment.address)
+                    .filter(|addr| is_host(&pool, **addr))
+                {
+                    index.take(*addr);
+                    assigned.insert(*addr);
+                }
+                let unclaimed = assigned
+                    .iter()
+                    .filter(|addr| self.owner_of(**addr).is_none())
+                    .count() as u128;
+                let total = index.free_count().saturating_add(assigned.len() as u128);
+                let used = self.used_count(&pool) as u128;
+                PoolUsage {
+                    network: pool.to_string(),
+                    total: total.try_into().unwrap_or(u64::MAX),
+                    used: used.try_into().unwrap_or(u64::MAX),
+                    free: total
+                        .saturating_sub(used + unclaimed)
+                        .try_into()
+                        .unwrap_or(u64::MAX),
+                }
+            })
+            .collect()
+    }
+    // число выданных пирам адресов пула
+    pub fn used_count(&self, pool: &IpNet) -> usize {
+        self.peers
+            .values()
+            .flat_map(HashMap::values)
+            .filter(|info| info.addresses.iter().any(|addr| pool.contains(addr)))
+            .count()
+    }
+    pub fn get_mut(&mut self, account: &str, public_key: &wg::PublicKey) -> Option<&mut PeerInfo> {
+        self.peers.get_mut(account)?.get_mut(public_key)
+    }
+    pub fn expired(&self, now: u64) -> Vec<(String, wg::PublicKey)> {
+        self.peers
+            .iter()
+            .flat_map(|(account, peers)| {
+                peers
+                    .iter()
+                    .filter(|(_, info)| info.is_expired(now))
+                    .map(|(public_key, _)| (account.clone(), *public_key))
+            })
+            .collect()
+    }
+    pub fn remove(&mut self, account: &str, public_key: &wg::PublicKey) -> Option<PeerInfo> {
+        let peers_of_account = self.peers.get_mut(account)?;
+        let removed = peers_of_account.remove(public_key);
+        if peers_of_account.is_empty() {
+            self.peers.remove(account);
+        }
+        for addr in removed.iter().flat_map(|info| &info.addresses) {
+            self.release(*addr);
+        }
+        removed
+    }
+}
+
+// хранилище, загруженное runserver, вместе с индексом адресов живет между
+// запросами; stamp - файл, из которого оно прочитано или в который записано
+struct Cached {
+    storage: Box<Storage>,
+    stamp: Option<FileStamp>,
+}
+
+// файл хранилища каждый раз заменяется новым, поэтому смена inode или mtime
+// означает, что его записал другой процесс
+#[derive(PartialEq)]
+struct FileStamp {
+    inode: u64,
+    modified: std::time::SystemTime,
+    len: u64,
+}
+
+async fn file_stamp() -> Option<FileStamp> {
+    use std::os::unix::fs::MetadataExt as _;
+    let metadata = fs::metadata(&CONFIG.storage).await.ok()?;
+    Some(FileStamp {
+        inode: metadata.ino(),
+        modified: metadata.modified().ok()?,
+        len: metadata.len(),
+    })
 }
 
-pub struct StorageLock<'a> {
+type Guard = tokio::sync::MutexGuard<'static, Option<Cached>>;
+
+pub struct StorageLock {
     storage: Option<Box<Storage>>,
-    _lock: tokio::sync::MutexGuard<'a, ()>,
+    lock: Option<(Guard, std::fs::File)>,
 }
 
-impl<'a> Deref for StorageLock<'a> {
+impl Deref for StorageLock {
     type Target = Storage;
 
     fn deref(&self) -> &Self::Target {
@@ -126,14 +925,14 @@ impl<'a> Deref for StorageLock<'a> {
     }
 }
 
-impl<'a> DerefMut for StorageLock<'a> {
+impl DerefMut for StorageLock {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.storage.as_deref_mut().unwrap()
     }
 }
 
 lazy_static! {
-    static ref STORAGE_MUTEX: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    static ref STORAGE: tokio::sync::Mutex<Option<Cached>> = tokio::sync::Mutex::new(None);
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -151,14 +950,14 @@ pub async fn commit_storage(storage: &Storage) -> std::result::Result<(), Commit
         "{}.tmp.dump",
         temp_path
             .file_name()
-            .map(|x| x.to_str())
-            .flatten()
+            .and_then(|x| x.to_str())
             .expect("cannot get storage filename")
     ));
 
     let mut temp_file = OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(&temp_path)
         .await?;
     let result = serde_yaml::to_string(&storage)?;
@@ -170,28 +969,300 @@ pub async fn commit_storage(storage: &Storage) -> std::result::Result<(), Commit
     Ok(())
 }
 
-impl<'a> Drop for StorageLock<'a> {
+// записывает хранилище и возвращает его в кэш, блокировки отпускаются после записи,
+// иначе следующий get_storage может прочитать устаревшее хранилище
+async fn commit_locked(
+    storage: Box<Storage>,
+    lock: Option<(Guard, std::fs::File)>,
+) -> Result<(), CommitError> {
+    commit_storage(&storage).await?;
+    if let Some((mut guard, _file)) = lock {
+        *guard = Some(Cached {
+            storage,
+            stamp: file_stamp().await,
+        });
+    }
+    Ok(())
+}
+
+impl StorageLock {
+    // в отличие от Drop дожидается записи, нужно командам, после которых процесс завершается
+    pub async fn commit(mut self) -> Result<(), CommitError> {
+        let storage = self.storage.take().unwrap();
+        commit_locked(storage, self.lock.take()).await
+    }
+    // возвращает неизмененное хранилище в кэш без записи
+    pub async fn discard(mut self) {
+        let storage = self.storage.take().unwrap();
+        if let Some((mut guard, _file)) = self.lock.take() {
+            *guard = Some(Cached {
+                storage,
+                stamp: file_stamp().await,
+            });
+        }
+    }
+}
+
+impl Drop for StorageLock {
     fn drop(&mut self) {
-        let mut val = Option::<Box<Storage>>::None;
-        std::mem::swap(&mut self.storage, &mut val);
+        let Some(val) = self.storage.take() else {
+            return;
+        };
+        let lock = self.lock.take();
         tokio::spawn(async move {
-            commit_storage(val.as_deref().unwrap())
+            commit_locked(val, lock)
                 .await
                 .expect("cannot commit_storage");
         });
     }
 }
 
-pub async fn get_storage() -> StorageLock<'static> {
-    let lock: tokio::sync::MutexGuard<'static, ()> = STORAGE_MUTEX.lock().await;
+// STORAGE действует только внутри процесса, а add, import и revoke
+// меняют хранилище параллельно с runserver, поэтому процессы договариваются
+// через flock на отдельном файле: сам файл хранилища заменяется при записи
+async fn lock_file() -> std::fs::File {
+    let mut path = CONFIG.storage.clone();
+    path.set_file_name(format!(
+        "{}.lock",
+        path.file_name()
+            .and_then(|x| x.to_str())
+            .expect("cannot get storage filename")
+    ));
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        file.lock()?;
+        Ok::<_, std::io::Error>(file)
+    })
+    .await
+    .unwrap()
+    .expect("cannot lock storage")
+}
+
+async fn load_storage() -> Storage {
     let mut file = tokio::fs::File::open(&CONFIG.storage).await.unwrap();
     let mut string = String::new();
     file.read_to_string(&mut string)
         .await
         .expect("cannot read storage");
+    serde_yaml::from_str(&string).unwrap()
+}
 
+// хранилище перечитывается, только если файл изменил другой процесс,
+// иначе берется из памяти вместе с уже построенным индексом адресов
+pub async fn get_storage() -> StorageLock {
+    let mut guard = STORAGE.lock().await;
+    let file = lock_file().await;
+    let stamp = file_stamp().await;
+    let storage = match guard.take() {
+        Some(cached) if stamp.is_some() && cached.stamp == stamp => cached.storage,
+        Some(cached) => {
+            let storage = Box::new(load_storage().await);
+            events::external_changes(&cached.storage.peers, &storage.peers);
+            storage
+        }
+        None => Box::new(load_storage().await),
+    };
     StorageLock {
-        storage: Some(Box::new(serde_yaml::from_str(&string).unwrap())),
-        _lock: lock,
+        storage: Some(storage),
+        lock: Some((guard, file)),
+    }
+}
+
+// чтение под блокировкой без записи хранилища
+pub async fn read_locked<T>(f: impl FnOnce(&Storage) -> T) -> T {
+    let lock = get_storage().await;
+    let result = f(&lock);
+    lock.discard().await;
+    result
+}
+
+// снимок хранилища только для чтения, ничего не блокирует и не записывает
+pub async fn read_storage() -> Storage {
+    load_storage().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::config::use_config;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn key(n: u8) -> wg::PublicKey {
+        wg::PublicKey::from([n; 32])
+    }
+
+    fn setup(addresses: &str, config: &str) -> Storage {
+        use_config(&format!(
+            "service: {{endpoint: 'example.com:55000'}}\nstorage: /nonexistent\ninterface: wg0\n{config}"
+        ));
+        serde_yaml::from_str(&format!(
+            "interface:
+  ListenPort: 55000
+  PrivateKey: CMDHxEO2ufo1Yz5itg0neKPr+j0QhgEeRl38Z8sbzGI=
+  Address: {addresses}
+server:
+  public_key: Y4pxVxprU+r3X2JU9fpp37vZ8gXO8A8+iV2NTw8IcHU=
+  endpoint: example.com:55000
+peers: {{}}
+"
+        ))
+        .unwrap()
+    }
+
+    fn reserve(
+        storage: &mut Storage,
+        account: &str,
+        public_key: wg::PublicKey,
+        requested: &[IpAddr],
+    ) -> Result<PeerInfo, AllocateError> {
+        storage.reserve(account, public_key, requested, None, &[], PeerInfo::from)
+    }
+
+    #[test]
+    fn static_addresses_go_only_to_their_peers() {
+        let mut storage = setup(
+            "10.11.0.1/24",
+            "static_assignments:
+  - account: alice
+    address: 10.11.0.2
+  - account: bob
+    public_key: BQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQU=
+    address: 10.11.0.3
+",
+        );
+        assert!(matches!(
+            reserve(&mut storage, "carol", key(1), &[addr("10.11.0.2")]),
+            Err(AllocateError::Reserved(..))
+        ));
+        let carol = reserve(&mut storage, "carol", key(1), &[]).unwrap();
+        assert_eq!(carol.addresses, [addr("10.11.0.4")]);
+        // без ключа в назначении адрес достается первому пиру account
+        let alice = reserve(&mut storage, "alice", key(2), &[]).unwrap();
+        assert_eq!(alice.addresses, [addr("10.11.0.2")]);
+        let alice = reserve(&mut storage, "alice", key(3), &[]).unwrap();
+        assert_eq!(alice.addresses, [addr("10.11.0.5")]);
+        assert!(matches!(
+            reserve(&mut storage, "bob", key(4), &[addr("10.11.0.3")]),
+            Err(AllocateError::Reserved(..))
+        ));
+        assert!(matches!(
+            reserve(&mut storage, "bob", key(5), &[addr("10.11.0.6")]),
+            Err(AllocateError::StaticMismatch(..))
+        ));
+        let bob = reserve(&mut storage, "bob", key(5), &[]).unwrap();
+        assert_eq!(bob.addresses, [addr("10.11.0.3")]);
+    }
+
+    #[test]
+    fn account_pool_rejects_when_full() {
+        let mut storage = setup(
+            "10.11.0.1/24",
+            "account_pools: {ipv4_prefix_len: 30, when_full: reject}\n",
+        );
+        let first = reserve(&mut storage, "a", key(1), &[]).unwrap();
+        assert_eq!(first.addresses, [addr("10.11.0.2")]);
+        assert!(matches!(
+            reserve(&mut storage, "b", key(2), &[addr("10.11.0.3")]),
+            Err(AllocateError::ForeignAccountPool(..))
+        ));
+        assert!(matches!(
+            reserve(&mut storage, "a", key(3), &[addr("10.11.0.9")]),
+            Err(AllocateError::OutsideAccountPool(..))
+        ));
+        reserve(&mut storage, "a", key(3), &[]).unwrap();
+        assert!(matches!(
+            reserve(&mut storage, "a", key(4), &[]),
+            Err(AllocateError::Exhausted(..))
+        ));
+        assert_eq!(storage.account_pools["a"], [net("10.11.0.0/30")]);
+    }
+
+    #[test]
+    fn account_pool_extends_past_excluded_subnets() {
+        let mut storage = setup(
+            "10.11.0.1/24",
+            "account_pools: {ipv4_prefix_len: 30, when_full: extend}
+exclude: [10.11.0.4/30]
+",
+        );
+        reserve(&mut storage, "a", key(1), &[]).unwrap();
+        reserve(&mut storage, "a", key(2), &[]).unwrap();
+        let third = reserve(&mut storage, "a", key(3), &[]).unwrap();
+        assert_eq!(third.addresses, [addr("10.11.0.8")]);
+        assert_eq!(
+            storage.account_pools["a"],
+            [net("10.11.0.0/30"), net("10.11.0.8/30")]
+        );
+    }
+
+    #[test]
+    fn failed_move_releases_claimed_subnets() {
+        let mut storage = setup(
+            "10.11.0.1/24,fd00::1/126",
+            "account_pools: {ipv4_prefix_len: 30, ipv6_prefix_len: 126}\n",
+        );
+        reserve(&mut storage, "b", key(1), &[]).unwrap();
+        // ipv4 подсеть для a находится, а единственная ipv6 подсеть уже у b
+        assert!(matches!(
+            storage.move_peer(&key(1), "a"),
+            Some(Err(AllocateError::Exhausted(..)))
+        ));
+        assert!(!storage.account_pools.contains_key("a"));
+        assert_eq!(storage.account_of(&key(1)), Some("b"));
+    }
+
+    #[test]
+    fn delegation_overlap_and_exhaustion() {
+        let mut storage = setup(
+            "10.11.0.1/24",
+            "delegation_pools: [10.12.0.0/28]
+advertised_routes:
+  a: [10.0.0.0/8]
+",
+        );
+        let mut delegate = |public_key, routes: &[IpNet]| {
+            storage.reserve("a", public_key, &[], Some(29), routes, PeerInfo::from)
+        };
+        assert_eq!(
+            delegate(key(1), &[]).unwrap().delegated,
+            [net("10.12.0.0/29")]
+        );
+        assert!(matches!(
+            delegate(key(2), &[net("10.12.0.8/29")]),
+            Err(AllocateError::RouteOverlapsPool(..))
+        ));
+        assert!(matches!(
+            delegate(key(2), &[net("10.11.0.0/25")]),
+            Err(AllocateError::RouteOverlapsPool(..))
+        ));
+        assert!(matches!(
+            delegate(key(2), &[net("10.13.0.1/24")]),
+            Err(AllocateError::HostBits(..))
+        ));
+        let second = delegate(key(2), &[net("10.13.0.0/24")]).unwrap();
+        assert_eq!(second.delegated, [net("10.12.0.8/29")]);
+        assert!(matches!(
+            delegate(key(3), &[net("10.13.0.0/25")]),
+            Err(AllocateError::RouteInUse(..))
+        ));
+        assert!(matches!(
+            delegate(key(3), &[]),
+            Err(AllocateError::Exhausted(..))
+        ));
+        assert!(matches!(
+            storage.reserve("a", key(3), &[], Some(24), &[], PeerInfo::from),
+            Err(AllocateError::NoDelegationPool(24))
+        ));
     }
 }
@@ -1,7 +1,7 @@
 use base64::{engine::general_purpose::STANDARD, Engine as _};
-use rand::rngs::OsRng;
+use rand::{rngs::OsRng, RngCore};
 use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
-pub use x25519_dalek::{PublicKey, StaticSecret as PrivateKey};
+pub use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret as PrivateKey};
 
 use serde_with::{DeserializeAs, SerializeAs};
 
@@ -32,6 +32,18 @@ impl KeyPair {
     }
 }
 
+// симметричный ключ пира, дополняющий обмен x25519
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PresharedKey([u8; 32]);
+
+impl PresharedKey {
+    pub fn gen() -> PresharedKey {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        PresharedKey(key)
+    }
+}
+
 pub trait FromBase64 {
     fn from_base_64(value: &str) -> Result<Self, ParseError>
     where
@@ -47,7 +59,7 @@ impl FromBase64 for PublicKey {
             .as_slice()
             .try_into()
             .map_err(|_| ParseError::IncorrectLength(value.to_string()))?;
-        Ok(bytes.clone().into())
+        Ok((*bytes).into())
     }
 }
 
@@ -60,10 +72,24 @@ impl FromBase64 for PrivateKey {
             .as_slice()
             .try_into()
             .map_err(|_| ParseError::IncorrectLength(value.to_string()))?;
-        Ok(bytes.clone().into())
+        Ok((*bytes).into())
     }
 }
 
+impl FromBase64 for PresharedKey {
+    fn from_base_64(value: &str) -> Result<Self, ParseError> {
+        let bytes_vec = STANDARD
+            .decode(value)
+            .map_err(|_| ParseError::NotBase64(value.to_string()))?;
+        let bytes: &[u8; 32] = bytes_vec
+            .as_slice()
+            .try_into()
+            .map_err(|_| ParseError::IncorrectLength(value.to_string()))?;
+        Ok(PresharedKey(*bytes))
+    }
+}
+
+#[allow(clippy::wrong_self_convention)]
 pub trait IntoBase64 {
     fn into_base_64(&self) -> String;
 }
@@ -80,6 +106,12 @@ impl IntoBase64 for PrivateKey {
     }
 }
 
+impl IntoBase64 for PresharedKey {
+    fn into_base_64(&self) -> String {
+        STANDARD.encode(self.0)
+    }
+}
+
 pub struct SerdeBase64 {}
 
 impl SerializeAs<PublicKey> for SerdeBase64 {
@@ -87,7 +119,7 @@ impl SerializeAs<PublicKey> for SerdeBase64 {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&STANDARD.encode(&source.as_bytes()))
+        serializer.serialize_str(&STANDARD.encode(source.as_bytes()))
     }
 }
 
@@ -96,7 +128,7 @@ impl SerializeAs<PrivateKey> for SerdeBase64 {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&STANDARD.encode(&source.as_bytes()))
+        serializer.serialize_str(&STANDARD.encode(source.as_bytes()))
     }
 }
 
@@ -105,7 +137,7 @@ impl SerializeAs<&PublicKey> for SerdeBase64 {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&STANDARD.encode(&(*source).as_bytes()))
+        serializer.serialize_str(&STANDARD.encode((*source).as_bytes()))
     }
 }
 
@@ -114,7 +146,16 @@ impl SerializeAs<&PrivateKey> for SerdeBase64 {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&STANDARD.encode(&(*source).as_bytes()))
+        serializer.serialize_str(&STANDARD.encode((*source).as_bytes()))
+    }
+}
+
+impl SerializeAs<PresharedKey> for SerdeBase64 {
+    fn serialize_as<S>(source: &PresharedKey, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&source.into_base_64())
     }
 }
 
@@ -138,6 +179,16 @@ impl<'de> DeserializeAs<'de, PrivateKey> for SerdeBase64 {
     }
 }
 
+impl<'de> DeserializeAs<'de, PresharedKey> for SerdeBase64 {
+    fn deserialize_as<D>(deserializer: D) -> Result<PresharedKey, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let string = String::deserialize(deserializer)?;
+        FromBase64::from_base_64(&string).map_err(|e| D::Error::custom(format!("{e}")))
+    }
+}
+
 // #[derive(dm::Into, dm::From, Clone)]
 // pub struct PrivateKeyBuf {
 //     key: PrivateKey,
@@ -0,0 +1,164 @@
+use std::process::{ExitStatus, Stdio};
+
+use ipnet::IpNet;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::common::wg::{FromBase64, IntoBase64, PresharedKey, PrivateKey, PublicKey};
+
+#[derive(thiserror::Error, Debug)]
+pub enum WgError {
+    #[error("failed to run wg: {}", .0)]
+    IO(#[from] std::io::Error),
+    #[error("wg finished with {}", .0)]
+    Status(ExitStatus),
+    #[error("cannot parse wg output: {}", .0)]
+    Parse(String),
+}
+
+impl From<WgError> for tonic::Status {
+    fn from(value: WgError) -> Self {
+        tonic::Status::internal(value.to_string())
+    }
+}
+
+// пир в том виде, в котором его видит ядро: одна строка `wg show <interface> dump`
+pub struct DumpPeer {
+    pub public_key: PublicKey,
+    pub preshared_key: Option<PresharedKey>,
+    pub allowed_ips: Vec<IpNet>,
+    // unix время последнего рукопожатия, 0 если его не было
+    pub latest_handshake: u64,
+}
+
+async fn run(args: &[&str]) -> Result<String, WgError> {
+    let output = Command::new("wg").args(args).output().await?;
+    if !output.status.success() {
+        return Err(WgError::Status(output.status));
+    }
+    String::from_utf8(output.stdout).map_err(|e| WgError::Parse(e.to_string()))
+}
+
+// секреты передаются через stdin, чтобы не светить их в списке процессов
+async fn run_with_stdin(args: &[&str], input: &str) -> Result<(), WgError> {
+    let mut child = Command::new("wg")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(input.as_bytes()).await?;
+    drop(stdin);
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(WgError::Status(status));
+    }
+    Ok(())
+}
+
+// без preshared_key у пира удаляется ранее заданный ключ
+pub async fn set_peer(
+    interface: &str,
+    public_key: &PublicKey,
+    allowed_ips: &[IpNet],
+    preshared_key: Option<&PresharedKey>,
+) -> Result<(), WgError> {
+    let allowed_ips = allowed_ips
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let public_key = public_key.into_base_64();
+    let (source, input) = match preshared_key {
+        Some(preshared_key) => ("/dev/stdin", preshared_key.into_base_64()),
+        None => ("/dev/null", String::new()),
+    };
+    run_with_stdin(
+        &[
+            "set",
+            interface,
+            "peer",
+            &public_key,
+            "allowed-ips",
+            &allowed_ips,
+            "preshared-key",
+            source,
+        ],
+        &input,
+    )
+    .await
+}
+
+pub async fn remove_peer(interface: &str, public_key: &PublicKey) -> Result<(), WgError> {
+    run(&[
+        "set",
+        interface,
+        "peer",
+        &public_key.into_base_64(),
+        "remove",
+    ])
+    .await?;
+    Ok(())
+}
+
+pub async fn public_key(interface: &str) -> Result<PublicKey, WgError> {
+    let output = run(&["show", interface, "public-key"]).await?;
+    PublicKey::from_base_64(output.trim()).map_err(|e| WgError::Parse(e.to_string()))
+}
+
+fn parse_dump_peer(line: &str) -> Result<DumpPeer, WgError> {
+    let error = || WgError::Parse(format!("unexpected peer line '{}'", line));
+    let fields: Vec<_> = line.split('\t').collect();
+    if fields.len() != 8 {
+        return Err(error());
+    }
+    let public_key = PublicKey::from_base_64(fields[0]).map_err(|_| error())?;
+    let preshared_key = match fields[1] {
+        "(none)" => None,
+        key => Some(PresharedKey::from_base_64(key).map_err(|_| error())?),
+    };
+    let allowed_ips = match fields[3] {
+        "(none)" => vec![],
+        ips => ips
+            .split(',')
+            .map(|x| x.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| error())?,
+    };
+    let latest_handshake = fields[4].parse().map_err(|_| error())?;
+    Ok(DumpPeer {
+        public_key,
+        preshared_key,
+        allowed_ips,
+        latest_handshake,
+    })
+}
+
+pub async fn dump(interface: &str) -> Result<Vec<DumpPeer>, WgError> {
+    let output = run(&["show", interface, "dump"]).await?;
+    // первая строка описывает сам интерфейс, остальные - пиры
+    output
+        .lines()
+        .skip(1)
+        .filter(|x| !x.is_empty())
+        .map(parse_dump_peer)
+        .collect()
+}
+
+pub async fn private_key(interface: &str) -> Result<PrivateKey, WgError> {
+    let output = run(&["show", interface, "private-key"]).await?;
+    PrivateKey::from_base_64(output.trim()).map_err(|e| WgError::Parse(e.to_string()))
+}
+
+// None, если fwmark у интерфейса не задан
+pub async fn fwmark(interface: &str) -> Result<Option<u32>, WgError> {
+    let output = run(&["show", interface, "fwmark"]).await?;
+    let output = output.trim();
+    if output == "off" {
+        return Ok(None);
+    }
+    let parsed = match output.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => output.parse(),
+    };
+    parsed.map(Some).map_err(|e| WgError::Parse(e.to_string()))
+}
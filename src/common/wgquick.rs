@@ -0,0 +1,97 @@
+use ipnet::IpNet;
+
+use crate::common::wg::{FromBase64, PresharedKey, PublicKey};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("line {}: {}", .0, .1)]
+    Line(usize, String),
+    #[error("peer in section starting at line {} has no PublicKey", .0)]
+    NoPublicKey(usize),
+}
+
+pub struct Peer {
+    pub public_key: PublicKey,
+    pub preshared_key: Option<PresharedKey>,
+    pub allowed_ips: Vec<IpNet>,
+}
+
+struct PeerSection {
+    line: usize,
+    public_key: Option<PublicKey>,
+    preshared_key: Option<PresharedKey>,
+    allowed_ips: Vec<IpNet>,
+}
+
+impl PeerSection {
+    fn finish(self) -> Result<Peer, ConfigError> {
+        Ok(Peer {
+            public_key: self.public_key.ok_or(ConfigError::NoPublicKey(self.line))?,
+            preshared_key: self.preshared_key,
+            allowed_ips: self.allowed_ips,
+        })
+    }
+}
+
+// разбирает секции [Peer] из конфигурации wg-quick или вывода `wg showconf`
+pub fn parse_peers(content: &str) -> Result<Vec<Peer>, ConfigError> {
+    let mut peers = vec![];
+    let mut current: Option<PeerSection> = None;
+    for (index, line) in content.lines().enumerate() {
+        let number = index + 1;
+        let line = match line.split_once('#') {
+            Some((line, _comment)) => line,
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            if let Some(section) = current.take() {
+                peers.push(section.finish()?);
+            }
+            if line.eq_ignore_ascii_case("[peer]") {
+                current = Some(PeerSection {
+                    line: number,
+                    public_key: None,
+                    preshared_key: None,
+                    allowed_ips: vec![],
+                });
+            }
+            continue;
+        }
+        let Some(section) = current.as_mut() else {
+            continue;
+        };
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| ConfigError::Line(number, "expected 'Key = Value'".into()))?;
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "publickey" => {
+                let public_key = PublicKey::from_base_64(value)
+                    .map_err(|e| ConfigError::Line(number, e.to_string()))?;
+                section.public_key = Some(public_key);
+            }
+            "presharedkey" => {
+                let preshared_key = PresharedKey::from_base_64(value)
+                    .map_err(|e| ConfigError::Line(number, e.to_string()))?;
+                section.preshared_key = Some(preshared_key);
+            }
+            "allowedips" => {
+                for net in value.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+                    let net = net.parse().map_err(|_| {
+                        ConfigError::Line(number, format!("incorrect allowed ip '{}'", net))
+                    })?;
+                    section.allowed_ips.push(net);
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(section) = current.take() {
+        peers.push(section.finish()?);
+    }
+    Ok(peers)
+}
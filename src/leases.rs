@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use crate::common::{
+    config::CONFIG,
+    events::{self, PeerEvent},
+    storage::{get_storage, now},
+    wg::IntoBase64,
+    wgcli,
+};
+
+// удаляет из хранилища и с интерфейса пиры с истекшей арендой
+// и неподтвержденные вовремя предложения.
+// ошибка wg не останавливает обход, оставшийся на интерфейсе пир уберет reconciler
+pub async fn reap() {
+    let mut storage = get_storage().await;
+    for (account, public_key) in storage.expired(now()) {
+        let Some(removed) = storage.remove(&account, &public_key) else {
+            continue;
+        };
+        if let Err(error) = wgcli::remove_peer(&CONFIG.interface, &public_key).await {
+            eprintln!(
+                "leases: cannot remove peer {} ({}) from interface: {}",
+                public_key.into_base_64(),
+                account,
+                error
+            );
+        }
+        let what = if removed.offered { "offer" } else { "lease" };
+        println!(
+            "leases: {} of peer {} ({}) expired",
+            what,
+            public_key.into_base_64(),
+            account
+        );
+        events::publish(PeerEvent::Removed {
+            account,
+            public_key,
+            info: removed,
+        });
+    }
+}
+
+pub async fn run() {
+    let period = Duration::from_secs(CONFIG.reap_interval);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        reap().await;
+    }
+}
@@ -0,0 +1,13 @@
+#![feature(map_try_insert)]
+#![feature(string_remove_matches)]
+
+pub mod common;
+
+pub mod admin_client;
+pub mod admin_service;
+pub mod auth;
+pub mod client;
+pub mod commands;
+pub mod leases;
+pub mod reconciler;
+pub mod service;
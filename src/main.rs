@@ -1,21 +1,13 @@
-#![feature(map_try_insert)]
-#![feature(string_remove_matches)]
-
-mod common;
-
-mod client;
-
 use std::error::Error;
 
 use clap::{Parser, Subcommand};
 
-pub mod commands;
-pub mod service;
+use wgdhc::{admin_client, client, commands};
 
 #[derive(Subcommand, Debug)]
 enum Command {
     #[command(name = "init", about = "inits storage, has to be run before runserver")]
-    Init,
+    Init(commands::init::Arguments),
     #[command(
         name = "runserver",
         about = "runs server with configuration from ~/.config/wgdhc.yaml"
@@ -23,8 +15,31 @@ enum Command {
     RunServer,
     #[command(name = "ls", about = "lists all profiles(works on server only)")]
     Ls,
+    #[command(
+        name = "export",
+        about = "prints server interface and all peers as wg-quick config(works on server only)"
+    )]
+    Export,
+    #[command(
+        name = "import",
+        about = "imports peers from wg-quick config or `wg showconf` output(works on server only)"
+    )]
+    Import(commands::import::Arguments),
+    #[command(
+        name = "add",
+        about = "registers peer with known public key and prints its config(works on server only)"
+    )]
+    Add(commands::add::Arguments),
+    #[command(
+        name = "revoke",
+        alias = "rm",
+        about = "removes peers by account, public key or address(works on server only)"
+    )]
+    Revoke(commands::revoke::Arguments),
+    #[command(name = "admin", about = "manages remote server through AdminService")]
+    Admin(admin_client::Arguments),
     #[command(name = "client", about = "inits client wg peer")]
-    Client(client::Arguments),
+    Client(Box<client::Arguments>),
 }
 
 #[derive(Parser, Debug)]
@@ -45,11 +60,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Command::Ls => {
             print!("{}", commands::ls::execute().await);
         }
+        Command::Export => {
+            print!("{}", commands::export::execute().await?);
+        }
+        Command::Import(args) => {
+            commands::import::execute(&args).await?;
+        }
+        Command::Add(args) => {
+            print!("{}", commands::add::execute(&args).await?);
+        }
+        Command::Revoke(args) => {
+            commands::revoke::execute(&args).await?;
+        }
+        Command::Admin(args) => {
+            print!("{}", admin_client::execute(&args).await?);
+        }
         Command::Client(args) => {
             client::execute(&args).await?;
         }
-        Command::Init => {
-            commands::init::execute().await?;
+        Command::Init(args) => {
+            commands::init::execute(&args).await?;
         }
     };
     Ok(())
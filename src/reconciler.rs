@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ipnet::IpNet;
+
+use crate::common::{
+    config::CONFIG,
+    events::{self, PeerEvent},
+    ipcli::{self, IpError},
+    storage::get_storage,
+    wg::{IntoBase64, PresharedKey, PublicKey},
+    wgcli::{self, WgError},
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReconcileError {
+    #[error("{}", .0)]
+    Wg(#[from] WgError),
+    #[error("{}", .0)]
+    Ip(#[from] IpError),
+}
+
+// приводит пиры интерфейса к состоянию из хранилища:
+// добавляет недостающие, удаляет неизвестные и исправляет allowed-ips.
+// первое рукопожатие предложенного пира считается подтверждением адреса.
+// маршруты к подсетям за пирами приводятся к объявленным пирами
+pub async fn reconcile() -> Result<(), ReconcileError> {
+    // хранилище держится заблокированным до конца сверки, чтобы не удалить
+    // пир, который сервис добавил между чтением хранилища и `wg show`
+    let mut storage = get_storage().await;
+    let mut expected: HashMap<PublicKey, (String, Vec<IpNet>, Option<PresharedKey>)> =
+        HashMap::new();
+    for (account, peers) in storage.peers.iter() {
+        for (public_key, info) in peers.iter() {
+            expected.insert(
+                *public_key,
+                (account.clone(), info.allowed_ips(), info.preshared_key),
+            );
+        }
+    }
+
+    let live = wgcli::dump(&CONFIG.interface).await?;
+    for peer in live.iter() {
+        match expected.remove(&peer.public_key) {
+            // ошибка с одним пиром не должна мешать сверке остальных
+            None => match wgcli::remove_peer(&CONFIG.interface, &peer.public_key).await {
+                Ok(()) => println!(
+                    "reconciler: removed unknown peer {}",
+                    peer.public_key.into_base_64()
+                ),
+                Err(error) => eprintln!(
+                    "reconciler: cannot remove unknown peer {}: {}",
+                    peer.public_key.into_base_64(),
+                    error
+                ),
+            },
+            Some((account, mut allowed_ips, preshared_key)) => {
+                if peer.latest_handshake != 0 {
+                    if let Some(info) = storage.get_mut(&account, &peer.public_key) {
+                        if info.offered {
+                            info.confirm();
+                            events::publish(PeerEvent::Renewed {
+                                account: account.clone(),
+                                public_key: peer.public_key,
+                                info: info.clone(),
+                            });
+                            println!(
+                                "reconciler: confirmed peer {} ({}) after handshake",
+                                peer.public_key.into_base_64(),
+                                account
+                            );
+                        }
+                    }
+                }
+                let mut live_ips = peer.allowed_ips.clone();
+                live_ips.sort();
+                allowed_ips.sort();
+                if live_ips != allowed_ips || peer.preshared_key != preshared_key {
+                    match wgcli::set_peer(
+                        &CONFIG.interface,
+                        &peer.public_key,
+                        &allowed_ips,
+                        preshared_key.as_ref(),
+                    )
+                    .await
+                    {
+                        Ok(()) => println!(
+                            "reconciler: updated peer {} ({}), allowed-ips {:?}",
+                            peer.public_key.into_base_64(),
+                            account,
+                            allowed_ips
+                        ),
+                        Err(error) => eprintln!(
+                            "reconciler: cannot update peer {} ({}): {}",
+                            peer.public_key.into_base_64(),
+                            account,
+                            error
+                        ),
+                    }
+                }
+            }
+        }
+    }
+
+    for (public_key, (account, allowed_ips, preshared_key)) in expected {
+        match wgcli::set_peer(
+            &CONFIG.interface,
+            &public_key,
+            &allowed_ips,
+            preshared_key.as_ref(),
+        )
+        .await
+        {
+            Ok(()) => println!(
+                "reconciler: added peer {} ({}) with allowed-ips {:?}",
+                public_key.into_base_64(),
+                account,
+                allowed_ips
+            ),
+            Err(error) => eprintln!(
+                "reconciler: cannot add peer {} ({}): {}",
+                public_key.into_base_64(),
+                account,
+                error
+            ),
+        }
+    }
+
+    let mut expected_routes: Vec<IpNet> = storage
+        .peers
+        .values()
+        .flat_map(|peers| peers.values())
+        .flat_map(|info| info.routes.iter().copied())
+        .collect();
+    for route in ipcli::routes(&CONFIG.interface).await? {
+        match expected_routes.iter().position(|x| *x == route) {
+            Some(i) => {
+                expected_routes.swap_remove(i);
+            }
+            // ошибка с одним маршрутом не должна мешать сверке остальных
+            None => match ipcli::remove_route(&CONFIG.interface, &route).await {
+                Ok(()) => println!("reconciler: removed route {}", route),
+                Err(error) => eprintln!("reconciler: cannot remove route {}: {}", route, error),
+            },
+        }
+    }
+    for route in expected_routes {
+        match ipcli::add_route(&CONFIG.interface, &route).await {
+            Ok(()) => println!("reconciler: added route {}", route),
+            Err(error) => eprintln!("reconciler: cannot add route {}: {}", route, error),
+        }
+    }
+    Ok(())
+}
+
+pub async fn run() {
+    let period = Duration::from_secs(CONFIG.reconcile_interval);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        if let Err(error) = reconcile().await {
+            eprintln!("reconciler: {}", error);
+        }
+    }
+}
@@ -1,77 +1,338 @@
 pub use crate::common::proto::{
     dhc_service_server::{DhcService, DhcServiceServer},
-    ReserveIpRequest, ReserveIpResponse,
+    ConfirmIpRequest, ConfirmIpResponse, GetChallengeRequest, GetChallengeResponse,
+    GetServerInfoRequest, ReleaseIpRequest, ReleaseIpResponse, RenewLeaseRequest,
+    RenewLeaseResponse, ReserveIpRequest, ReserveIpResponse, ServerInfo,
 };
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use ipnet::IpNet;
+use std::net::IpAddr;
 use tonic::Response;
 
+use crate::auth;
 use crate::common::{
     config::CONFIG,
+    events::{self, PeerEvent},
+    ipcli, proof,
     storage::{self, PeerInfo},
     wg::{self, FromBase64, IntoBase64 as _, PublicKey},
+    wgcli,
 };
 
 pub struct ServiceImpl {}
 
-async fn wireguard_add_peer(public_key: &wg::PublicKey, info: &PeerInfo) -> tonic::Result<()> {
-    let allowed_ips = IpNet::new(info.internal_addr.clone(), 0).unwrap();
-    let allowed_ips = IpNet::new(info.internal_addr.clone(), allowed_ips.max_prefix_len()).unwrap();
-    let pub_key: String = public_key.into_base_64();
-    let status = tokio::process::Command::new("wg")
-        .args([
-            "set",
-            &CONFIG.interface,
-            "peer",
-            &pub_key,
-            "allowed-ips",
-            &allowed_ips.to_string(),
-        ])
-        .status()
-        .await
-        .map_err(|err| tonic::Status::internal(format!("failed to run wg: {}", err)))?;
-
-    if !status.success() {
-        return Err(tonic::Status::internal(format!(
-            "wg finished with {status}"
-        )));
+pub async fn wireguard_add_peer(public_key: &wg::PublicKey, info: &PeerInfo) -> tonic::Result<()> {
+    wgcli::set_peer(
+        &CONFIG.interface,
+        public_key,
+        &info.allowed_ips(),
+        info.preshared_key.as_ref(),
+    )
+    .await?;
+    // маршруты удаленных пиров убирает reconciler
+    for route in &info.routes {
+        ipcli::add_route(&CONFIG.interface, route).await?;
     }
     Ok(())
 }
 
+// возможности, которые зависят от конфигурации сервера
+pub fn capabilities() -> Vec<String> {
+    let mut capabilities = vec!["proof_of_possession", "confirm"];
+    if CONFIG.preshared_keys {
+        capabilities.push("preshared_keys");
+    }
+    if CONFIG.lease_duration.is_some() {
+        capabilities.push("leases");
+    }
+    if CONFIG.auth.is_some() {
+        capabilities.push("auth");
+    }
+    if CONFIG.service.tls.is_some() {
+        capabilities.push("tls");
+    }
+    if !CONFIG.admin.is_empty() {
+        capabilities.push("admin");
+    }
+    if !CONFIG.delegation_pools.is_empty() {
+        capabilities.push("prefix_delegation");
+    }
+    if !CONFIG.advertised_routes.is_empty() {
+        capabilities.push("advertised_routes");
+    }
+    capabilities.into_iter().map(str::to_string).collect()
+}
+
+// AllowedIPs пира сервера на стороне клиента
+fn client_allowed_ips(storage: &storage::Storage, public_key: &PublicKey) -> Vec<String> {
+    CONFIG
+        .client
+        .allowed_ips(&storage.interface.addresses)
+        .iter()
+        .chain(&storage.advertised_routes(public_key))
+        .map(IpNet::to_string)
+        .collect()
+}
+
+fn reserve_response(
+    storage: &storage::Storage,
+    public_key: &PublicKey,
+    peer: &PeerInfo,
+) -> ReserveIpResponse {
+    let addresses: Vec<_> = storage
+        .interface_addresses(peer)
+        .iter()
+        .map(IpNet::to_string)
+        .collect();
+    ReserveIpResponse {
+        address: addresses.first().cloned().unwrap_or_default(),
+        addresses,
+        server_public_key: storage.server.public_key.into_base_64(),
+        endpoint: (&storage.server.endpoint).into(),
+        expires_at: peer.expires_at.unwrap_or(0),
+        offered: peer.offered,
+        allowed_ips: client_allowed_ips(storage, public_key),
+        dns: CONFIG.client.dns.iter().map(IpAddr::to_string).collect(),
+        mtu: CONFIG.client.mtu.map_or(0, u32::from),
+        persistent_keepalive: u32::from(CONFIG.client.persistent_keepalive),
+        preshared_key: peer
+            .preshared_key
+            .map(|key| key.into_base_64())
+            .unwrap_or_default(),
+        delegated_prefixes: peer.delegated.iter().map(IpNet::to_string).collect(),
+    }
+}
+
+// интерфейс меняется до хранилища, чтобы при ошибке wg ничего не пришлось откатывать
+async fn rotate_key(
+    account: &str,
+    previous: PublicKey,
+    public_key: PublicKey,
+) -> tonic::Result<ReserveIpResponse> {
+    let mut storage = storage::get_storage().await;
+    if let Some(owner) = storage.account_of(&public_key) {
+        return Err(storage::AllocateError::KeyInUse(owner.to_string()).into());
+    }
+    let info = storage
+        .get_mut(account, &previous)
+        .ok_or(tonic::Status::not_found("no such peer for the account"))?
+        .clone();
+    wireguard_add_peer(&public_key, &info).await?;
+    wgcli::remove_peer(&CONFIG.interface, &previous).await?;
+    storage.rotate_key(account, &previous, public_key);
+    events::publish(PeerEvent::KeyRotated {
+        account: account.to_string(),
+        old_public_key: previous,
+        public_key,
+        info: info.clone(),
+    });
+    Ok(reserve_response(&storage, &public_key, &info))
+}
+
 #[tonic::async_trait]
 impl DhcService for ServiceImpl {
+    async fn get_challenge(
+        &self,
+        request: tonic::Request<GetChallengeRequest>,
+    ) -> tonic::Result<tonic::Response<GetChallengeResponse>> {
+        let account = request
+            .extensions()
+            .get::<auth::AuthenticatedAccount>()
+            .map(|auth::AuthenticatedAccount(account)| account.as_str());
+        let (nonce, server_key) =
+            proof::issue(account).map_err(|e| tonic::Status::resource_exhausted(e.to_string()))?;
+        Ok(Response::new(GetChallengeResponse {
+            nonce: STANDARD.encode(nonce),
+            server_key: server_key.into_base_64(),
+        }))
+    }
+
     async fn reserve_ip(
         &self,
         request: tonic::Request<ReserveIpRequest>,
     ) -> tonic::Result<tonic::Response<ReserveIpResponse>> {
-        let ans = {
-            let mut storage = storage::get_storage().await;
-            let req = request.get_ref();
-            let ip = storage.find_ip().ok_or(tonic::Status::resource_exhausted(
-                "all ip addresses are in use",
-            ))?;
-            let new_peer = PeerInfo {
-                internal_addr: ip.clone(),
-            };
-            let public_key: PublicKey = FromBase64::from_base_64(&req.public_key).map_err(|e| {
-                tonic::Status::invalid_argument(format!("incorrect public key: {e}"))
-            })?;
-            let new_peer = storage.push(&req.account, public_key.clone(), new_peer.clone());
+        let req = request.get_ref();
+        auth::check_account(&request, &req.account)?;
+        let public_key: PublicKey = FromBase64::from_base_64(&req.public_key)
+            .map_err(|e| tonic::Status::invalid_argument(format!("incorrect public key: {e}")))?;
+        let nonce = STANDARD
+            .decode(&req.nonce)
+            .map_err(|_| tonic::Status::invalid_argument("nonce is not base64"))?;
+        let proof = STANDARD
+            .decode(&req.proof)
+            .map_err(|_| tonic::Status::invalid_argument("proof is not base64"))?;
+        proof::verify(&nonce, &public_key, &req.account, &proof)
+            .map_err(|e| tonic::Status::permission_denied(e.to_string()))?;
 
-            wireguard_add_peer(&public_key, &new_peer).await?;
+        // пустая строка означает любой свободный адрес
+        let requested = match req.requested_address.as_str() {
+            "" => vec![],
+            address => vec![address.parse::<IpAddr>().map_err(|e| {
+                tonic::Status::invalid_argument(format!("incorrect requested address: {e}"))
+            })?],
+        };
 
-            let internal_addr = IpNet::new(
-                new_peer.internal_addr,
-                storage.interface.address.prefix_len(),
-            )
-            .expect("cannot create new address with mask from address and mask");
+        let delegated_prefix_len = match req.delegated_prefix_len {
+            0 => None,
+            prefix_len => Some(u8::try_from(prefix_len).map_err(|_| {
+                tonic::Status::invalid_argument("incorrect delegated prefix length")
+            })?),
+        };
 
-            ReserveIpResponse {
-                address: internal_addr.to_string(),
-                server_public_key: storage.server.public_key.into_base_64(),
-                endpoint: (&storage.server.endpoint).into(),
+        let routes = req
+            .advertised_routes
+            .iter()
+            .map(|route| route.parse::<IpNet>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| tonic::Status::invalid_argument(format!("incorrect route: {e}")))?;
+
+        if !req.previous_public_key.is_empty() {
+            // при смене ключа пир сохраняет адреса, префиксы и маршруты
+            if !requested.is_empty() || delegated_prefix_len.is_some() || !routes.is_empty() {
+                return Err(tonic::Status::invalid_argument(
+                    "address, prefix and routes cannot be changed with the key",
+                ));
             }
-        };
-        Ok(Response::new(ans))
+            let previous = FromBase64::from_base_64(&req.previous_public_key).map_err(|e| {
+                tonic::Status::invalid_argument(format!("incorrect previous public key: {e}"))
+            })?;
+            // иначе знающий чужой публичный ключ мог бы забрать адреса пира
+            let previous_nonce = STANDARD
+                .decode(&req.previous_nonce)
+                .map_err(|_| tonic::Status::invalid_argument("previous nonce is not base64"))?;
+            let previous_proof = STANDARD
+                .decode(&req.previous_proof)
+                .map_err(|_| tonic::Status::invalid_argument("previous proof is not base64"))?;
+            proof::verify(&previous_nonce, &previous, &req.account, &previous_proof)
+                .map_err(|e| tonic::Status::permission_denied(e.to_string()))?;
+            return rotate_key(&req.account, previous, public_key)
+                .await
+                .map(Response::new);
+        }
+
+        let mut storage = storage::get_storage().await;
+        let existing = storage.account_of(&public_key).is_some();
+        // в режиме drain новые ключи не принимаются, уже известные продолжают работать
+        if storage.draining && !existing {
+            return Err(tonic::Status::unavailable(
+                "server is draining, new peers are not accepted",
+            ));
+        }
+        let new_peer = storage.reserve(
+            &req.account,
+            public_key,
+            &requested,
+            delegated_prefix_len,
+            &routes,
+            PeerInfo::offer,
+        )?;
+
+        if let Err(error) = wireguard_add_peer(&public_key, &new_peer).await {
+            if !existing {
+                storage.remove(&req.account, &public_key);
+            }
+            return Err(error);
+        }
+        if !existing {
+            events::publish(PeerEvent::Added {
+                account: req.account.clone(),
+                public_key,
+                info: new_peer.clone(),
+            });
+        }
+        Ok(Response::new(reserve_response(
+            &storage,
+            &public_key,
+            &new_peer,
+        )))
+    }
+
+    async fn release_ip(
+        &self,
+        request: tonic::Request<ReleaseIpRequest>,
+    ) -> tonic::Result<tonic::Response<ReleaseIpResponse>> {
+        let mut storage = storage::get_storage().await;
+        let req = request.get_ref();
+        auth::check_account(&request, &req.account)?;
+        let public_key: PublicKey = FromBase64::from_base_64(&req.public_key)
+            .map_err(|e| tonic::Status::invalid_argument(format!("incorrect public key: {e}")))?;
+        if storage.get_mut(&req.account, &public_key).is_none() {
+            return Err(tonic::Status::not_found("no such peer for the account"));
+        }
+        wgcli::remove_peer(&CONFIG.interface, &public_key).await?;
+        let info = storage.remove(&req.account, &public_key).unwrap();
+        events::publish(PeerEvent::Removed {
+            account: req.account.clone(),
+            public_key,
+            info,
+        });
+        Ok(Response::new(ReleaseIpResponse {}))
+    }
+    async fn renew_lease(
+        &self,
+        request: tonic::Request<RenewLeaseRequest>,
+    ) -> tonic::Result<tonic::Response<RenewLeaseResponse>> {
+        let mut storage = storage::get_storage().await;
+        let req = request.get_ref();
+        auth::check_account(&request, &req.account)?;
+        let public_key: PublicKey = FromBase64::from_base_64(&req.public_key)
+            .map_err(|e| tonic::Status::invalid_argument(format!("incorrect public key: {e}")))?;
+        let peer = storage
+            .get_mut(&req.account, &public_key)
+            .ok_or(tonic::Status::not_found("no such peer for the account"))?;
+        if peer.offered {
+            return Err(tonic::Status::failed_precondition(
+                "address is only offered, confirm it first",
+            ));
+        }
+        peer.expires_at = storage::lease_expiry();
+        let info = peer.clone();
+        events::publish(PeerEvent::Renewed {
+            account: req.account.clone(),
+            public_key,
+            info: info.clone(),
+        });
+        Ok(Response::new(RenewLeaseResponse {
+            expires_at: info.expires_at.unwrap_or(0),
+            allowed_ips: client_allowed_ips(&storage, &public_key),
+        }))
+    }
+
+    async fn confirm_ip(
+        &self,
+        request: tonic::Request<ConfirmIpRequest>,
+    ) -> tonic::Result<tonic::Response<ConfirmIpResponse>> {
+        let mut storage = storage::get_storage().await;
+        let req = request.get_ref();
+        auth::check_account(&request, &req.account)?;
+        let public_key: PublicKey = FromBase64::from_base_64(&req.public_key)
+            .map_err(|e| tonic::Status::invalid_argument(format!("incorrect public key: {e}")))?;
+        let peer = storage
+            .get_mut(&req.account, &public_key)
+            .ok_or(tonic::Status::not_found("no such peer for the account"))?;
+        peer.confirm();
+        let info = peer.clone();
+        events::publish(PeerEvent::Renewed {
+            account: req.account.clone(),
+            public_key,
+            info: info.clone(),
+        });
+        Ok(Response::new(ConfirmIpResponse {
+            expires_at: info.expires_at.unwrap_or(0),
+        }))
+    }
+
+    async fn get_server_info(
+        &self,
+        _request: tonic::Request<GetServerInfoRequest>,
+    ) -> tonic::Result<tonic::Response<ServerInfo>> {
+        let storage = storage::read_storage().await;
+        Ok(Response::new(ServerInfo {
+            public_key: storage.server.public_key.into_base_64(),
+            endpoint: (&storage.server.endpoint).into(),
+            pools: storage.pool_usage(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: capabilities(),
+        }))
     }
 }